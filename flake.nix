@@ -35,7 +35,8 @@
 
         craneLib = crane.lib.${system}.overrideToolchain fenix-toolchain;
 
-        # filter source code at path `src` to include only the list of `modules`
+        # filter source code at path `src` to include only the list of `services` `modules`
+        # (and `rustshop-env` they depend on)
         filterModules = modules: src:
           let
             basePath = toString src + "/";
@@ -45,10 +46,10 @@
               let
                 relPath = lib.removePrefix basePath (toString path);
                 includePath =
-                  (type == "directory" && builtins.match "^[^/]+$" relPath != null) ||
+                  (type == "directory" && builtins.match "^(services|rustshop)(/[^/]+)?$" relPath != null) ||
                   lib.any
                     (re: builtins.match re relPath != null)
-                    ([ "Cargo.lock" "Cargo.toml" ".*/Cargo.toml" ] ++ builtins.concatLists (map (name: [ name "${name}/.*" ]) modules));
+                    ([ "services/Cargo.lock" "services/Cargo.toml" "services/.*/Cargo.toml" "rustshop/env/.*" ] ++ builtins.concatLists (map (name: [ "services/${name}" "services/${name}/.*" ]) modules));
               in
               # uncomment to debug:
                 # builtins.trace "${relPath}: ${lib.boolToString includePath}"
@@ -69,10 +70,10 @@
         #
         # Lile `filterWorkspaceFiles` but doesn't even need *.rs files
         # (because they are not used for building dependencies)
-        filterWorkspaceDepsBuildFiles = src: filterSrcWithRegexes [ "Cargo.lock" "Cargo.toml" ".*/Cargo.toml" ] src;
+        filterWorkspaceDepsBuildFiles = src: filterSrcWithRegexes [ "services/Cargo.lock" "services/Cargo.toml" "services/.*/Cargo.toml" "rustshop/env/Cargo.toml" ] src;
 
         # Filter only files relevant to building the workspace
-        filterWorkspaceFiles = src: filterSrcWithRegexes [ "Cargo.lock" "Cargo.toml" ".*/Cargo.toml" ".*\.rs" ] src;
+        filterWorkspaceFiles = src: filterSrcWithRegexes [ "services/Cargo.lock" "services/Cargo.toml" "services/.*/Cargo.toml" "services/.*\.rs" "rustshop/env/Cargo.toml" "rustshop/env/.*\.rs" ] src;
 
         filterSrcWithRegexes = regexes: src:
           let
@@ -95,8 +96,16 @@
             inherit src;
           };
 
+        # `services` depend on `rustshop-env` from the `rustshop` workspace, so the sources
+        # are taken from the repository root, and the build runs in `services/`
         commonArgs = {
-          src = filterWorkspaceFiles ./services;
+          src = filterWorkspaceFiles ./.;
+          cargoLock = ./services/Cargo.lock;
+          cargoToml = ./services/Cargo.toml;
+          postUnpack = ''
+            cd $sourceRoot/services
+            sourceRoot="."
+          '';
           buildInputs = [
           ];
           nativeBuildInputs = [
@@ -106,7 +115,7 @@
         };
 
         workspaceDeps = craneLib.buildDepsOnly (commonArgs // {
-          src = filterWorkspaceDepsBuildFiles ./services;
+          src = filterWorkspaceDepsBuildFiles ./.;
           pname = "services-workspace-deps";
           doCheck = false;
         });
@@ -131,7 +140,7 @@
             cargoArtifacts = workspaceDeps;
             pname = name;
 
-            src = filterModules [ "common-app" name ] ./services;
+            src = filterModules [ "common-app" "common-tracing" name ] ./.;
 
             cargoExtraArgs = "--bin ${name}";
           });
@@ -158,7 +167,7 @@
             cargoArtifacts = workspaceDeps;
             pname = name;
 
-            src = filterModules [ "common-res-gen" "common-app" name ] ./services;
+            src = filterModules [ "common-res-gen" "common-app" "common-tracing" name ] ./.;
 
             cargoExtraArgs = "--bin ${name}";
           });
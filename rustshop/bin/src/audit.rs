@@ -0,0 +1,362 @@
+//! Local audit log of commands executed against shop accounts
+//!
+//! Every command run through `rustshop` wrapping (and `rustshop exec`) is
+//! appended as a JSON line to `.rustshop/state/audit/<account>.jsonl`.
+//! Optionally each entry is also piped to a shop-wide hook command
+//! (`audit_hook` in `shop.yaml`), e.g. to ship it to a central log sink.
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+};
+
+use chrono::{DateTime, Utc};
+use derive_more::Display;
+use error_stack::{bail, Context, Result, ResultExt};
+use rustshop_env::{ContextYaml, Env, EnvRoot};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+#[derive(Debug, Display)]
+pub enum AuditError {
+    #[display(fmt = "IO Error")]
+    Io,
+    #[display(fmt = "Serialization error")]
+    Serde,
+    #[display(fmt = "Audit hook exited with {}", status)]
+    Hook { status: ExitStatus },
+}
+
+impl Context for AuditError {}
+
+pub type AuditResult<T> = Result<T, AuditError>;
+
+const REDACTED: &str = "<redacted>";
+
+/// Set by `rustshop exec` to its pid, for the command it already recorded
+const AUDITED_BY_ENV_NAME: &str = "RUSTSHOP_AUDITED_BY";
+
+/// Prefixes of secret-looking flags that don't take a value, e.g. `--no-token`
+const BOOLEAN_FLAG_PREFIXES: &[&str] = &["no-", "show-", "ask-", "prompt-", "generate-"];
+
+/// Parts of flag/variable names that suggest the value is a secret
+///
+/// Keep in sync with `services/common-app/src/opts.rs`.
+const SECRET_NAME_PARTS: &[&str] = &[
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "credential",
+    "private-key",
+    "private_key",
+    "access-key",
+    "access_key",
+    "apikey",
+    "api-key",
+    "api_key",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+    pub account: String,
+    pub cluster: Option<String>,
+    pub namespace: Option<String>,
+    pub bin: String,
+    pub args: Vec<String>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        context: &ContextYaml,
+        bin: &str,
+        args: &[impl AsRef<std::ffi::OsStr>],
+    ) -> Option<Self> {
+        Some(Self {
+            timestamp: Utc::now(),
+            user: std::env::var("USER").unwrap_or_else(|_| "unknown".into()),
+            account: context.account.clone()?,
+            cluster: context.cluster.clone(),
+            namespace: context.namespace.clone(),
+            bin: bin.to_owned(),
+            args: redact_args(
+                &args
+                    .iter()
+                    .map(|arg| arg.as_ref().to_string_lossy().to_string())
+                    .collect::<Vec<_>>(),
+            ),
+        })
+    }
+}
+
+impl std::fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            self.user,
+            self.account
+        )?;
+        if let Some(cluster) = self.cluster.as_ref() {
+            write!(f, "/{cluster}")?;
+            if let Some(namespace) = self.namespace.as_ref() {
+                write!(f, ":{namespace}")?;
+            }
+        }
+        write!(f, " {}", self.bin)?;
+        for arg in &self.args {
+            write!(f, " {arg}")?;
+        }
+        Ok(())
+    }
+}
+
+fn is_secret_name(name: &str) -> bool {
+    let name = name.trim_start_matches('-').to_lowercase();
+    SECRET_NAME_PARTS.iter().any(|part| name.contains(part))
+}
+
+/// Does `--flag` (without `=`) look like it takes a secret as the next argument
+///
+/// Stricter than `is_secret_name`, as redacting the argument after a boolean
+/// flag (like `--password-stdin` or `--show-secrets`) would hide an unrelated
+/// one.
+fn is_secret_value_flag(flag: &str) -> bool {
+    let name = flag.trim_start_matches('-').to_lowercase();
+    SECRET_NAME_PARTS.iter().any(|part| name.ends_with(part))
+        && !BOOLEAN_FLAG_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+/// Redact `name=value` if `name` looks like a secret
+fn redact_assignment(assignment: &str) -> Option<String> {
+    let (name, _value) = assignment.split_once('=')?;
+    is_secret_name(name).then(|| format!("{name}={REDACTED}"))
+}
+
+/// Redact values of secret-looking flags and variables
+///
+/// Handles `--flag value`, `--flag=value` and `name=value` (like in
+/// `terraform -var` or `helm --set`) forms.
+pub fn redact_args(args: &[String]) -> Vec<String> {
+    let mut redacted = Vec::with_capacity(args.len());
+    let mut redact_next = false;
+
+    for arg in args {
+        if std::mem::take(&mut redact_next) && !arg.starts_with('-') {
+            redacted.push(REDACTED.to_owned());
+            continue;
+        }
+
+        if arg.starts_with('-') {
+            if let Some((flag, value)) = arg.split_once('=') {
+                if is_secret_name(flag) {
+                    redacted.push(format!("{flag}={REDACTED}"));
+                } else if let Some(value) = redact_assignment(value) {
+                    redacted.push(format!("{flag}={value}"));
+                } else {
+                    redacted.push(arg.clone());
+                }
+            } else {
+                redact_next = arg != "--" && is_secret_value_flag(arg);
+                redacted.push(arg.clone());
+            }
+        } else if let Some(arg) = redact_assignment(arg) {
+            redacted.push(arg);
+        } else {
+            redacted.push(arg.clone());
+        }
+    }
+
+    redacted
+}
+
+fn audit_dir(root: &EnvRoot) -> PathBuf {
+    root.state_dir().join("audit")
+}
+
+fn audit_log_path(root: &EnvRoot, account: &str) -> PathBuf {
+    audit_dir(root).join(format!("{account}.jsonl"))
+}
+
+/// Append an entry to the audit log (and pass it to the hook)
+pub fn record(env: &Env, entry: &AuditEntry) -> AuditResult<()> {
+    let mut line = serde_json::to_string(entry).change_context(AuditError::Serde)?;
+    line.push('\n');
+
+    let path = audit_log_path(env, &entry.account);
+    fs::create_dir_all(audit_dir(env)).change_context(AuditError::Io)?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .change_context(AuditError::Io)?;
+    // single write, so concurrent invocations don't interleave lines
+    file.write_all(line.as_bytes())
+        .change_context(AuditError::Io)?;
+
+    if let Some(hook) = env.audit_hook() {
+        if let Err(e) = run_hook(hook, &line) {
+            warn!("Audit hook failed: {e:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Like `record`, but only warns on failure
+///
+/// Auditing must never prevent the user from doing their work.
+pub fn record_or_warn(env: &Env, entry: Option<AuditEntry>) {
+    if let Some(entry) = entry {
+        if let Err(e) = record(env, &entry) {
+            warn!("Could not write audit log: {e:?}");
+        }
+    }
+}
+
+/// Mark `cmd` as already recorded, so a wrapper it execs doesn't record it again
+///
+/// Wrapper scripts `exec` into `rustshop wrap`, so the wrapper's parent is the
+/// process that started `cmd`. Anything started by `cmd` itself (e.g. in
+/// `rustshop shell`) is still recorded.
+pub fn mark_recorded(cmd: &mut Command) {
+    cmd.env(AUDITED_BY_ENV_NAME, std::process::id().to_string());
+}
+
+/// Was this process already recorded by its parent (see `mark_recorded`)
+pub fn is_recorded_by_parent() -> bool {
+    std::env::var(AUDITED_BY_ENV_NAME).ok().as_deref()
+        == Some(&std::os::unix::process::parent_id().to_string())
+}
+
+/// Run the hook with the entry on its stdin and wait for it
+///
+/// The command is only started after the hook finishes, so hooks should be quick.
+fn run_hook(hook: &str, line: &str) -> AuditResult<()> {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", hook])
+        .env(Env::NO_BIN_WRAP_ENV_NAME, "true")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null());
+
+    debug!("Running audit hook: {cmd:?}");
+    let mut child = cmd.spawn().change_context(AuditError::Io)?;
+    // dropping stdin closes it, so the hook sees the end of input
+    let write_res = child
+        .stdin
+        .take()
+        .expect("stdin piped")
+        .write_all(line.as_bytes());
+    let status = child.wait().change_context(AuditError::Io)?;
+    write_res.change_context(AuditError::Io)?;
+
+    if !status.success() {
+        bail!(AuditError::Hook { status });
+    }
+    Ok(())
+}
+
+/// Load audit log entries, oldest first
+pub fn load_entries(root: &EnvRoot, account: Option<&str>) -> AuditResult<Vec<AuditEntry>> {
+    let paths = if let Some(account) = account {
+        vec![audit_log_path(root, account)]
+    } else {
+        match fs::read_dir(audit_dir(root)) {
+            Ok(dir) => dir
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().map(|ext| ext == "jsonl").unwrap_or(false))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => Err(e).change_context(AuditError::Io)?,
+        }
+    };
+
+    let mut entries = vec![];
+    for path in paths {
+        if !path.exists() {
+            continue;
+        }
+        let file = fs::File::open(&path).change_context(AuditError::Io)?;
+        for line in io::BufReader::new(file).lines() {
+            let line = line.change_context(AuditError::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => warn!(path = %path.display(), "Skipping malformed audit entry: {e}"),
+            }
+        }
+    }
+
+    entries.sort_by_key(|entry: &AuditEntry| entry.timestamp);
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact(args: &str) -> String {
+        redact_args(&args.split(' ').map(ToString::to_string).collect::<Vec<_>>()).join(" ")
+    }
+
+    #[test]
+    fn redacts_value_after_secret_flag() {
+        assert_eq!(
+            redact("login --password hunter2 registry"),
+            "login --password <redacted> registry"
+        );
+        assert_eq!(redact("--db-password hunter2"), "--db-password <redacted>");
+        assert_eq!(redact("-token abc"), "-token <redacted>");
+    }
+
+    #[test]
+    fn redacts_inline_flag_value() {
+        assert_eq!(
+            redact("--client-secret=abc --region=us-east-1"),
+            "--client-secret=<redacted> --region=us-east-1"
+        );
+    }
+
+    #[test]
+    fn redacts_assignments() {
+        assert_eq!(
+            redact("apply -var db_password=abc -var size=3"),
+            "apply -var db_password=<redacted> -var size=3"
+        );
+        assert_eq!(
+            redact("--set=auth.apiKey=abc"),
+            "--set=auth.apiKey=<redacted>"
+        );
+    }
+
+    #[test]
+    fn keeps_argument_after_boolean_flag() {
+        assert_eq!(
+            redact("login --password-stdin registry"),
+            "login --password-stdin registry"
+        );
+        assert_eq!(redact("get --show-secrets pods"), "get --show-secrets pods");
+        assert_eq!(redact("--no-token deploy"), "--no-token deploy");
+    }
+
+    #[test]
+    fn keeps_flag_after_secret_flag() {
+        assert_eq!(redact("--token --verbose get"), "--token --verbose get");
+    }
+
+    #[test]
+    fn keeps_unrelated_args() {
+        assert_eq!(
+            redact("get pods -n default -- tokens"),
+            "get pods -n default -- tokens"
+        );
+    }
+}
@@ -1,11 +1,15 @@
+use chrono::{DateTime, Utc};
 use derive_more::Display;
 use error_stack::{bail, Context, Result, ResultExt};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use rustshop_env::Env;
 
-use std::process::Command;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
 
-use serde::{de::DeserializeOwned, Deserialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::{debug, trace};
 
 #[derive(Debug, Display)]
@@ -40,10 +44,21 @@ pub struct CallerIdentity {
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all(deserialize = "PascalCase"))]
+pub struct SecretValue {
+    pub secret_string: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
 pub struct Credentials {
-    pub session_token: String,
+    /// Not present for static IAM user keys
+    #[serde(default)]
+    pub session_token: Option<String>,
     pub access_key_id: String,
     pub secret_access_key: String,
+    /// Not present for long-lived credentials
+    #[serde(default)]
+    pub expiration: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -163,24 +178,32 @@ impl Aws {
         })
     }
 
+    fn new_cmd(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::new("aws");
+        // We do NOT want to re-wrap the `aws` command we issue directly here
+        cmd.env(Env::NO_BIN_WRAP_ENV_NAME, "true");
+        cmd.env("AWS_REGION", &self.region);
+
+        if let Some(creds) = self.credentials.as_ref() {
+            cmd.env("AWS_ACCESS_KEY_ID", &creds.access_key_id)
+                .env("AWS_SECRET_ACCESS_KEY", &creds.secret_access_key)
+                .env_remove("AWS_PROFILE");
+            match creds.session_token.as_ref() {
+                Some(session_token) => cmd.env("AWS_SESSION_TOKEN", session_token),
+                None => cmd.env_remove("AWS_SESSION_TOKEN"),
+            };
+        } else if let Some(profile) = self.profile.as_ref() {
+            // we only want to set profile if we are not using session tokens
+            cmd.arg("--profile").arg(profile);
+        }
+
+        cmd.args(args);
+        cmd
+    }
+
     fn run_cmd_raw(&self, args: &[&str], ignore_254: bool) -> AwsResult<Option<Vec<u8>>> {
         let output = {
-            let mut cmd = Command::new("aws");
-            // We do NOT want to re-wrap the `aws` command we issue directly here
-            cmd.env(Env::NO_BIN_WRAP_ENV_NAME, "true");
-            cmd.env("AWS_REGION", &self.region);
-
-            if let Some(creds) = self.credentials.as_ref() {
-                cmd.env("AWS_SESSION_TOKEN", &creds.session_token)
-                    .env("AWS_ACCESS_KEY_ID", &creds.access_key_id)
-                    .env("AWS_SECRET_ACCESS_KEY", &creds.secret_access_key)
-                    .env_remove("AWS_PROFILE");
-            } else if let Some(profile) = self.profile.as_ref() {
-                // we only want to set profile if we are not using session tokens
-                cmd.arg("--profile").arg(profile);
-            }
-
-            cmd.args(args);
+            let mut cmd = self.new_cmd(args);
 
             trace!("Running: {:?}", cmd);
             cmd.output().change_context(AwsError::Io)?
@@ -305,6 +328,139 @@ impl Aws {
         Ok(())
     }
 
+    /// Get a value from the profile configuration, if set
+    pub fn configure_get(&self, name: &str) -> AwsResult<Option<String>> {
+        let mut cmd = self.new_cmd(&["configure", "get", name]);
+
+        trace!("Running: {:?}", cmd);
+        let output = cmd.output().change_context(AwsError::Io)?;
+
+        // `aws configure get` exits with `1` when the value is not set
+        Ok(if output.status.success() {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        } else {
+            None
+        })
+    }
+
+    /// Start IAM Identity Center (SSO) login flow
+    ///
+    /// Interactive: user might need to confirm the login in the browser.
+    pub fn sso_login(&self) -> AwsResult<()> {
+        let mut cmd = self.new_cmd(&["sso", "login"]);
+
+        trace!("Running: {:?}", cmd);
+        let status = cmd.status().change_context(AwsError::Io)?;
+
+        if !status.success() {
+            bail!(AwsError::CommandFailed {
+                stderr: "`aws sso login` failed".into()
+            });
+        }
+        Ok(())
+    }
+
+    /// Resolve the profile into a set of (usually temporary) credentials
+    ///
+    /// Interactive: `aws` will prompt for an MFA code on stdin if
+    /// the profile requires it.
+    pub fn export_credentials(&self) -> AwsResult<Credentials> {
+        let args = ["configure", "export-credentials", "--format", "process"];
+        let mut cmd = self.new_cmd(&args);
+        cmd.stdin(Stdio::inherit()).stderr(Stdio::inherit());
+
+        trace!("Running: {:?}", cmd);
+        let output = cmd.output().change_context(AwsError::Io)?;
+
+        if !output.status.success() {
+            bail!(AwsError::CommandFailed {
+                stderr: "`aws configure export-credentials` failed".into()
+            });
+        }
+
+        serde_json::from_slice(&output.stdout).change_context(AwsError::ResponseDeserialization {
+            cmd: args.iter().map(ToString::to_string).collect(),
+        })
+    }
+
+    /// Like `run_cmd_raw`, but passing `input` on stdin
+    ///
+    /// For values (like secrets) that must not be visible in the process list.
+    fn run_cmd_raw_with_input(&self, args: &[&str], input: &[u8]) -> AwsResult<Vec<u8>> {
+        let mut cmd = self.new_cmd(args);
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        trace!("Running: {:?}", cmd);
+        let mut child = cmd.spawn().change_context(AwsError::Io)?;
+        child
+            .stdin
+            .take()
+            .expect("stdin piped")
+            .write_all(input)
+            .change_context(AwsError::Io)?;
+        let output = child.wait_with_output().change_context(AwsError::Io)?;
+
+        trace!("Status code: {:?}", output.status.code());
+        if !output.status.success() {
+            bail!(AwsError::CommandFailed {
+                stderr: String::from_utf8_lossy(&output.stderr).to_string()
+            });
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Get the value of a Secrets Manager secret, `None` if it doesn't exist
+    pub fn get_secret_string(&self, id: &str) -> AwsResult<Option<String>> {
+        let args = ["secretsmanager", "get-secret-value", "--secret-id", id];
+        let mut cmd = self.new_cmd(&args);
+
+        trace!("Running: {:?}", cmd);
+        let output = cmd.output().change_context(AwsError::Io)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+            if stderr.contains("ResourceNotFoundException") {
+                return Ok(None);
+            }
+            bail!(AwsError::CommandFailed { stderr });
+        }
+
+        let value: SecretValue = serde_json::from_slice(&output.stdout).change_context(
+            AwsError::ResponseDeserialization {
+                cmd: args.iter().map(ToString::to_string).collect(),
+            },
+        )?;
+        Ok(value.secret_string)
+    }
+
+    /// Set the value of a Secrets Manager secret, creating it if `create` is set
+    pub fn put_secret_string(&self, id: &str, value: &str, create: bool) -> AwsResult<()> {
+        let args = if create {
+            [
+                "secretsmanager",
+                "create-secret",
+                "--name",
+                id,
+                "--secret-string",
+                "file:///dev/stdin",
+            ]
+        } else {
+            [
+                "secretsmanager",
+                "put-secret-value",
+                "--secret-id",
+                id,
+                "--secret-string",
+                "file:///dev/stdin",
+            ]
+        };
+        self.run_cmd_raw_with_input(&args, value.as_bytes())?;
+        Ok(())
+    }
+
     pub fn list_hosted_zones(&self) -> AwsResult<Vec<HostedZone>> {
         Ok(self
             .run_cmd::<ListHostedZones>(&["route53", "list-hosted-zones"], false)?
@@ -0,0 +1,238 @@
+//! Cached AWS credentials
+//!
+//! Resolving credentials for a profile that assumes a role (possibly with MFA)
+//! or uses IAM Identity Center (SSO) is slow and interactive. After `rustshop login`
+//! the resulting temporary credentials are stored (encrypted) under
+//! `.rustshop/state/creds/` and reused by wrapped commands until they expire.
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use chrono::{Duration, Utc};
+use derive_more::Display;
+use error_stack::{bail, Context, Result, ResultExt};
+use rustshop_env::{Env, EnvRoot};
+use tracing::{debug, info, warn};
+
+use crate::aws_api::{Aws, Credentials};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Display)]
+pub enum CredsError {
+    #[display(fmt = "IO Error")]
+    Io,
+    #[display(fmt = "Could not locate user config directory")]
+    NoConfigDir,
+    #[display(fmt = "Credentials cache corrupted: {}", "path.display()")]
+    Corrupted { path: PathBuf },
+    #[display(fmt = "Could not resolve credentials for account: {}", account)]
+    Resolution { account: String },
+    #[display(fmt = "Loading rustshop env failed")]
+    EnvFailure,
+}
+
+impl Context for CredsError {}
+
+pub type CredsResult<T> = Result<T, CredsError>;
+
+pub struct CredsCache {
+    dir: PathBuf,
+    key_path: PathBuf,
+}
+
+impl CredsCache {
+    pub fn new(root: &EnvRoot) -> CredsResult<Self> {
+        // Key is kept outside of the shop repository, so copying the repo
+        // around does not leak usable credentials.
+        let key_path = dirs::config_dir()
+            .ok_or(CredsError::NoConfigDir)?
+            .join("rustshop")
+            .join("creds.key");
+
+        Ok(Self {
+            dir: root.state_dir().join("creds"),
+            key_path,
+        })
+    }
+
+    fn path(&self, account: &str) -> PathBuf {
+        self.dir.join(format!("{account}.bin"))
+    }
+
+    /// Was `rustshop login` ever used for this account
+    pub fn is_logged_in(&self, account: &str) -> bool {
+        self.path(account).exists()
+    }
+
+    /// Load cached credentials, unless missing or (about to be) expired
+    pub fn load(&self, account: &str) -> CredsResult<Option<Credentials>> {
+        let path = self.path(account);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = fs::read(&path).change_context(CredsError::Io)?;
+        if data.len() < NONCE_LEN {
+            bail!(CredsError::Corrupted { path });
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+        let plaintext = self
+            .cipher()?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: account.as_bytes(),
+                },
+            )
+            .map_err(|_| CredsError::Corrupted { path: path.clone() })?;
+
+        let creds: Credentials = serde_json::from_slice(&plaintext)
+            .change_context_lazy(|| CredsError::Corrupted { path: path.clone() })?;
+
+        match creds.expiration {
+            Some(expiration) if Utc::now() + Duration::minutes(5) < expiration => Ok(Some(creds)),
+            _ => {
+                debug!(account, "Cached credentials expired");
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn store(&self, account: &str, creds: &Credentials) -> CredsResult<()> {
+        let plaintext = serde_json::to_vec(creds).change_context(CredsError::Io)?;
+        let nonce: [u8; NONCE_LEN] = rand::random();
+
+        let ciphertext = self
+            .cipher()?
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: account.as_bytes(),
+                },
+            )
+            .map_err(|_| CredsError::Io)?;
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+
+        write_private_file(&self.path(account), &data).change_context(CredsError::Io)?;
+        Ok(())
+    }
+
+    fn cipher(&self) -> CredsResult<ChaCha20Poly1305> {
+        let key = if self.key_path.exists() {
+            let key = fs::read(&self.key_path).change_context(CredsError::Io)?;
+            if key.len() != KEY_LEN {
+                bail!(CredsError::Corrupted {
+                    path: self.key_path.clone()
+                });
+            }
+            key
+        } else {
+            debug!(path = %self.key_path.display(), "Generating credentials cache key");
+            let key: [u8; KEY_LEN] = rand::random();
+            write_private_file(&self.key_path, &key).change_context(CredsError::Io)?;
+            key.to_vec()
+        };
+
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+/// Write a file readable only by the current user
+fn write_private_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(path.parent().expect("Not a root path"))?;
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    drop(file);
+    fs::rename(tmp_path, path)
+}
+
+/// Resolve fresh credentials for an account and cache them
+///
+/// Goes through IAM Identity Center login first, if the account's
+/// profile is using it. Role assumption and MFA prompts are handled by `aws`.
+pub fn login(env: &Env, account: &str) -> CredsResult<Credentials> {
+    let account_ref = env
+        .get_account_ref(account)
+        .change_context(CredsError::EnvFailure)?;
+
+    let aws = Aws::new(
+        Some(account_ref.user.aws_profile.clone()),
+        account_ref.shop.bootstrap_aws_region.clone(),
+    );
+
+    let creds = match aws.export_credentials() {
+        Ok(creds) => creds,
+        Err(e) if is_sso_profile(&aws) => {
+            debug!("Credentials export failed: {e:?}");
+            info!(profile = account_ref.user.aws_profile, "Starting SSO login");
+            aws.sso_login()
+                .change_context_lazy(|| CredsError::Resolution {
+                    account: account.to_owned(),
+                })?;
+            aws.export_credentials()
+                .change_context_lazy(|| CredsError::Resolution {
+                    account: account.to_owned(),
+                })?
+        }
+        Err(e) => Err(e).change_context_lazy(|| CredsError::Resolution {
+            account: account.to_owned(),
+        })?,
+    };
+
+    if creds.expiration.is_none() {
+        warn!(
+            profile = account_ref.user.aws_profile,
+            "Profile uses long-lived credentials; not caching"
+        );
+        return Ok(creds);
+    }
+
+    CredsCache::new(env)?.store(account, &creds)?;
+
+    Ok(creds)
+}
+
+fn is_sso_profile(aws: &Aws) -> bool {
+    ["sso_start_url", "sso_session"]
+        .iter()
+        .any(|name| matches!(aws.configure_get(name), Ok(Some(_))))
+}
+
+/// Get cached credentials for an account, refreshing them if expired
+///
+/// Returns `None` for accounts that were never logged into with `rustshop login`.
+pub fn get_or_refresh(env: &Env, account: &str) -> CredsResult<Option<Credentials>> {
+    let cache = CredsCache::new(env)?;
+
+    if !cache.is_logged_in(account) {
+        return Ok(None);
+    }
+
+    if let Some(creds) = cache.load(account)? {
+        return Ok(Some(creds));
+    }
+
+    info!(account, "Cached credentials expired, logging in again");
+    Ok(Some(login(env, account)?))
+}
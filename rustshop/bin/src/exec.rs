@@ -0,0 +1,285 @@
+//! Running arbitrary commands in an explicitly selected context
+//!
+//! Unlike `wrap`, which only knows about certain binaries, this sets up all
+//! the environment variables rustshop knows about, so anything started
+//! (including a whole shell) will operate in the selected context, without
+//! changing the persisted one.
+use std::{
+    env,
+    ffi::OsString,
+    io::{self, Write},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::PathBuf,
+    process::{self, Command, ExitStatus},
+};
+
+use derive_more::Display;
+use error_stack::{Context, Result, ResultExt};
+use rustshop_env::{ContextYaml, Env, EnvContext};
+use tempfile::NamedTempFile;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    audit::{self, AuditEntry},
+    creds, wrap,
+};
+
+#[derive(Debug, Display)]
+pub enum ExecError {
+    #[display(fmt = "Loading rustshop env failed")]
+    EnvFailure,
+    #[display(fmt = "Could not prepare kubeconfig")]
+    Kubeconfig,
+    #[display(fmt = "Exec failed")]
+    ExecFailed,
+}
+
+impl Context for ExecError {}
+
+pub type ExecResult<T> = Result<T, ExecError>;
+
+/// Run `cmd` in the selected context and exit with its exit code
+pub fn exec_in_context(
+    context_overrides: ContextYaml,
+    bin: OsString,
+    args: &[OsString],
+) -> ExecResult<()> {
+    let env = Env::load().change_context(ExecError::EnvFailure)?;
+    let context = env
+        .get_context_with_overrides(context_overrides)
+        .change_context(ExecError::EnvFailure)?;
+
+    run_in_context(&env, context, bin, args)
+}
+
+/// Start user's shell in the selected context
+pub fn shell_in_context(context_overrides: ContextYaml) -> ExecResult<()> {
+    let shell = env::var_os("SHELL").unwrap_or_else(|| "sh".into());
+
+    let env = Env::load().change_context(ExecError::EnvFailure)?;
+    let context = env
+        .get_context_with_overrides(context_overrides)
+        .change_context(ExecError::EnvFailure)?;
+    env.write_ctx_info_to(context.clone(), &mut std::io::stderr())
+        .change_context(ExecError::EnvFailure)?;
+
+    run_in_context(&env, context, shell, &[])
+}
+
+fn run_in_context(
+    env: &Env,
+    context: EnvContext,
+    bin: OsString,
+    args: &[OsString],
+) -> ExecResult<()> {
+    let mut cmd = Command::new(&bin);
+    cmd.args(args);
+
+    // must be kept alive until the command exits
+    let kubeconfig = set_context_envs_on(env, &context, &mut cmd)?;
+
+    audit::record_or_warn(
+        env,
+        AuditEntry::new(&context.into(), &bin.to_string_lossy(), args),
+    );
+    audit::mark_recorded(&mut cmd);
+
+    trace!("Run: {cmd:?}");
+    let status = status_ignoring_signals(&mut cmd).change_context(ExecError::ExecFailed)?;
+
+    // `exit` skips destructors
+    drop(kubeconfig);
+    process::exit(exit_code(status));
+}
+
+/// Run `cmd` to completion, leaving `SIGINT` and `SIGQUIT` to it
+///
+/// The terminal sends these (Ctrl-C, Ctrl-\\) to the whole foreground
+/// process group, so like `system(3)`, ignore them while waiting, to always
+/// get to clean up after the child. The child gets the default handling back.
+fn status_ignoring_signals(cmd: &mut Command) -> io::Result<ExitStatus> {
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGQUIT];
+
+    // SAFETY: `signal` is async-signal-safe, so fine to call between `fork` and `exec`
+    unsafe {
+        cmd.pre_exec(|| {
+            for signal in SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            Ok(())
+        });
+    }
+
+    // SAFETY: only changes the disposition of signals, restored below
+    let prev = SIGNALS.map(|signal| unsafe { libc::signal(signal, libc::SIG_IGN) });
+    let status = cmd.status();
+    for (signal, handler) in SIGNALS.into_iter().zip(prev) {
+        // SAFETY: restoring what was there before
+        unsafe { libc::signal(signal, handler) };
+    }
+
+    status
+}
+
+/// Exit code like a shell would report it: `128 + signal` for killed commands
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+/// Set all the env variables for the `context` on `cmd`
+///
+/// Returns the generated kubeconfig file, if any, which needs to outlive the command.
+pub fn set_context_envs_on(
+    env: &Env,
+    context: &EnvContext,
+    cmd: &mut Command,
+) -> ExecResult<Option<NamedTempFile>> {
+    // nested `rustshop` calls (including wrapped binaries) should stay in this context
+    let context_path = ContextYaml::from(context.clone());
+    for (name, val) in [
+        (ContextYaml::ACCOUNT_ENV_NAME, &context_path.account),
+        (ContextYaml::CLUSTER_ENV_NAME, &context_path.cluster),
+        (ContextYaml::NAMESPACE_ENV_NAME, &context_path.namespace),
+    ] {
+        if let Some(val) = val {
+            cmd.env(name, val);
+        } else {
+            cmd.env_remove(name);
+        }
+    }
+
+    let (account_name, account_cfg) = if let Some(account) = context.account.as_ref() {
+        account
+    } else {
+        return Ok(None);
+    };
+
+    let creds = match creds::get_or_refresh(env, account_name) {
+        Ok(creds) => creds,
+        Err(e) => {
+            warn!("Could not use cached credentials, falling back to `AWS_PROFILE`: {e:?}");
+            None
+        }
+    };
+
+    if let Some(creds) = creds.as_ref() {
+        wrap::set_aws_creds_envs_on(creds, cmd);
+    } else {
+        wrap::set_aws_envs_on(account_cfg, cmd);
+    }
+    wrap::set_tf_aws_envs_on(env, account_cfg, creds.is_some(), cmd)
+        .change_context(ExecError::EnvFailure)?;
+
+    let (_cluster_name, cluster_cfg) = if let Some(cluster) = context.cluster.as_ref() {
+        cluster
+    } else {
+        return Ok(None);
+    };
+
+    wrap::set_kops_envs_on(&account_cfg.shop, &cluster_cfg.shop, cmd)
+        .change_context(ExecError::EnvFailure)?;
+
+    let kubeconfig = write_kubeconfig(&cluster_cfg.user.kube_ctx, context.namespace.as_deref())?;
+
+    let mut kubeconfig_paths = vec![kubeconfig.path().to_owned()];
+    kubeconfig_paths.extend(user_kubeconfig_paths());
+    let kubeconfig_env = env::join_paths(kubeconfig_paths).change_context(ExecError::Kubeconfig)?;
+
+    debug!(KUBECONFIG = ?kubeconfig_env, "Setting");
+    cmd.env("KUBECONFIG", kubeconfig_env);
+
+    Ok(Some(kubeconfig))
+}
+
+/// Kubeconfig files as `kubectl` would use them
+fn user_kubeconfig_paths() -> Vec<PathBuf> {
+    match env::var_os("KUBECONFIG") {
+        Some(paths) if !paths.is_empty() => env::split_paths(&paths).collect(),
+        _ => dirs::home_dir()
+            .map(|home| vec![home.join(".kube").join("config")])
+            .unwrap_or_default(),
+    }
+}
+
+/// Write a kubeconfig selecting `kube_ctx` (and `namespace`)
+///
+/// The file is meant to be put first in `KUBECONFIG`, so its `current-context`
+/// (and the context definition) take precedence over user's kubeconfigs.
+fn write_kubeconfig(kube_ctx: &str, namespace: Option<&str>) -> ExecResult<NamedTempFile> {
+    use serde_yaml::{Mapping, Value};
+
+    let mut kubeconfig = Mapping::new();
+    kubeconfig.insert("apiVersion".into(), "v1".into());
+    kubeconfig.insert("kind".into(), "Config".into());
+    kubeconfig.insert("current-context".into(), kube_ctx.into());
+
+    if let Some(namespace) = namespace {
+        // To change the namespace, the whole context definition needs to be copied
+        if let Some(mut context) = find_user_kube_context(kube_ctx) {
+            context.insert("namespace".into(), namespace.into());
+
+            let mut named_context = Mapping::new();
+            named_context.insert("name".into(), kube_ctx.into());
+            named_context.insert("context".into(), Value::Mapping(context));
+            kubeconfig.insert(
+                "contexts".into(),
+                Value::Sequence(vec![Value::Mapping(named_context)]),
+            );
+        } else {
+            warn!(
+                kube_ctx,
+                "Context not found in kubeconfig; namespace will not be set"
+            );
+        }
+    }
+
+    let mut file = NamedTempFile::new().change_context(ExecError::Kubeconfig)?;
+    let text = serde_yaml::to_string(&kubeconfig).change_context(ExecError::Kubeconfig)?;
+    file.write_all(text.as_bytes())
+        .change_context(ExecError::Kubeconfig)?;
+    file.flush().change_context(ExecError::Kubeconfig)?;
+
+    Ok(file)
+}
+
+fn find_user_kube_context(kube_ctx: &str) -> Option<serde_yaml::Mapping> {
+    user_kubeconfig_paths()
+        .into_iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .filter_map(|text| serde_yaml::from_str::<serde_yaml::Value>(&text).ok())
+        .find_map(|kubeconfig| {
+            kubeconfig
+                .get("contexts")?
+                .as_sequence()?
+                .iter()
+                .find(|context| context.get("name").and_then(|n| n.as_str()) == Some(kube_ctx))?
+                .get("context")?
+                .as_mapping()
+                .cloned()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        // raw wait statuses: exit code in the second byte, signal in the first
+        assert_eq!(exit_code(ExitStatus::from_raw(0)), 0);
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(exit_code(ExitStatus::from_raw(libc::SIGINT)), 130);
+        assert_eq!(exit_code(ExitStatus::from_raw(libc::SIGKILL)), 137);
+    }
+
+    #[test]
+    fn child_gets_default_signal_handling() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "kill -INT $$"]);
+        let status = status_ignoring_signals(&mut cmd).unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGINT));
+    }
+}
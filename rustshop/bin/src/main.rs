@@ -7,11 +7,18 @@ use rustshop_env as env;
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod audit;
 mod aws_api;
 mod bootstrap;
+mod creds;
+mod exec;
 mod opts;
+mod prompt;
+mod secret;
 mod wrap;
-use opts::{AddCommands, BootstrapCommands, Commands, EmailBootstrapOpts, GetCommands, Opts};
+use opts::{
+    AddCommands, BootstrapCommands, Commands, EmailBootstrapOpts, GetCommands, Opts, SecretCommands,
+};
 
 #[derive(Debug, Display)]
 pub enum AppError {
@@ -212,6 +219,57 @@ fn main_inner() -> AppResult<()> {
                 }
             }
         },
+        Commands::Login { account } => {
+            let env = Env::load().change_context(AppError::Other)?;
+
+            let account = if let Some(account) = account {
+                account
+            } else {
+                env.get_context_account()
+                    .change_context(AppError::Other)?
+                    .account
+                    .expect("get_account_context took care of it")
+                    .0
+            };
+
+            let creds = creds::login(&env, &account).change_context(AppError::Other)?;
+            if let Some(expiration) = creds.expiration {
+                info!("Logged into {account}; credentials valid until {expiration}");
+            }
+        }
+        Commands::Exec { context, mut cmd } => {
+            let bin = cmd.remove(0);
+            exec::exec_in_context(context.into(), bin, &cmd).change_context(AppError::Other)?
+        }
+        Commands::Shell { context } => {
+            exec::shell_in_context(context.into()).change_context(AppError::Other)?
+        }
+        Commands::Secret(SecretCommands::Set { account, name, key }) => {
+            let env = Env::load().change_context(AppError::Other)?;
+            secret::set(&env, account, &name, &key).change_context(AppError::Other)?;
+        }
+        Commands::Secret(SecretCommands::Get { account, name, key }) => {
+            let env = Env::load().change_context(AppError::Other)?;
+            let value = secret::get(&env, account, &name, key.as_deref())
+                .change_context(AppError::Other)?;
+            println!("{value}");
+        }
+        Commands::History { account, limit } => {
+            let env = EnvRoot::load().change_context(AppError::Other)?;
+            let entries =
+                audit::load_entries(&env, account.as_deref()).change_context(AppError::Other)?;
+            let skip = limit
+                .map(|limit| entries.len().saturating_sub(limit))
+                .unwrap_or(0);
+            for entry in entries.iter().skip(skip) {
+                println!("{entry}");
+            }
+        }
+        Commands::Prompt {
+            format,
+            no_color,
+            shell,
+        } => prompt::print_prompt(&format, !no_color, shell),
         Commands::Wrap { bin, args } => {
             wrap::exec_wrapped_bin(bin, args).change_context(AppError::Other)?
         }
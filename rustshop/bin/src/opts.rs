@@ -69,12 +69,112 @@ pub enum Commands {
     #[clap(subcommand)]
     Get(GetCommands),
 
+    /// Resolve and cache credentials for an account
+    ///
+    /// Goes through role assumption, MFA prompts or SSO login (as configured in
+    /// the account's `aws` profile) once, and caches the resulting temporary
+    /// credentials for wrapped commands to use until they expire.
+    Login {
+        /// Account name. Defaults to the current context account.
+        account: Option<String>,
+    },
+
+    /// Run a command in a given context, without switching to it
+    ///
+    /// Sets all the environment variables (`AWS_PROFILE`, `KOPS_*`, `TF_VAR_*`,
+    /// `KUBECONFIG`) for the selected context.
+    Exec {
+        #[clap(flatten)]
+        context: ContextOpts,
+
+        /// Command to run, after `--`
+        #[clap(last = true, required = true)]
+        cmd: Vec<OsString>,
+    },
+
+    /// Start a subshell in a given context, without switching to it
+    Shell {
+        #[clap(flatten)]
+        context: ContextOpts,
+    },
+
+    /// Manage application secrets (stored in AWS Secrets Manager)
+    #[clap(subcommand)]
+    Secret(SecretCommands),
+
+    /// Display the audit log of commands executed against accounts
+    History {
+        /// Only show commands executed against a given account
+        #[clap(long = "account")]
+        account: Option<String>,
+
+        /// Only show the last N entries
+        #[clap(long = "limit", short = 'n')]
+        limit: Option<usize>,
+    },
+
+    /// Print current context for a shell prompt
+    ///
+    /// Prints nothing when outside of a shop, or if no account is selected.
+    Prompt {
+        /// Output template; supports `{shop}`, `{account}`, `{cluster}` and `{namespace}`
+        #[clap(long = "format", default_value = crate::prompt::DEFAULT_FORMAT)]
+        format: String,
+
+        /// Don't color the output using the account's `color` from `shop.yaml`
+        #[clap(long = "no-color")]
+        no_color: bool,
+
+        /// Shell to escape the color codes for
+        #[clap(long = "shell", arg_enum, default_value = "plain")]
+        shell: PromptShell,
+    },
+
     /// Wrap a bin supplying rustshop specific arguments and environment
     #[clap(hide = true, disable_help_flag = true)]
     #[clap(allow_hyphen_values = true)]
     Wrap { bin: OsString, args: Vec<OsString> },
 }
 
+#[derive(Debug, Subcommand, Clone)]
+pub enum SecretCommands {
+    /// Set a key of a secret in the current account
+    Set {
+        /// Account name. Defaults to the current context account.
+        #[clap(long = "account")]
+        account: Option<String>,
+
+        /// Secret name (e.g. the app using it)
+        name: String,
+
+        /// Key within the secret (e.g. the env var the app reads it from)
+        ///
+        /// The value is read from stdin (prompted for, if it's a terminal), to
+        /// keep it out of the shell history, process list and audit log.
+        key: String,
+    },
+
+    /// Print a key of a secret in the current account (or all keys, as JSON)
+    Get {
+        /// Account name. Defaults to the current context account.
+        #[clap(long = "account")]
+        account: Option<String>,
+
+        /// Secret name
+        name: String,
+
+        /// Key within the secret
+        key: Option<String>,
+    },
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy)]
+pub enum PromptShell {
+    Plain,
+    Bash,
+    Zsh,
+}
+
 #[derive(Debug, Subcommand, Clone)]
 pub enum AddCommands {
     Shop {
@@ -239,3 +339,26 @@ pub enum GetCommands {
     Cluster,
     Namespace,
 }
+
+/// Explicitly selected context; unset parts default to the current context
+#[derive(Parser, Debug, Clone)]
+pub struct ContextOpts {
+    #[clap(long = "account")]
+    pub account: Option<String>,
+
+    #[clap(long = "cluster")]
+    pub cluster: Option<String>,
+
+    #[clap(long = "namespace", short = 'n')]
+    pub namespace: Option<String>,
+}
+
+impl From<ContextOpts> for rustshop_env::ContextYaml {
+    fn from(opts: ContextOpts) -> Self {
+        Self {
+            account: opts.account,
+            cluster: opts.cluster,
+            namespace: opts.namespace,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Shell prompt / status line integration
+//!
+//! Has to be fast, as it's executed on every prompt render: only reads the
+//! local config files, never calls out to anything. Never fails: prints
+//! nothing if there's nothing (valid) to print.
+use rustshop_env::{ContextYaml, EnvRoot};
+
+use crate::opts::PromptShell;
+
+pub const DEFAULT_FORMAT: &str = "{account}/{cluster}:{namespace}";
+
+pub fn print_prompt(format: &str, color: bool, shell: PromptShell) {
+    if let Some(prompt) = render_prompt(format, color, shell) {
+        print!("{prompt}");
+    }
+}
+
+fn render_prompt(format: &str, color: bool, shell: PromptShell) -> Option<String> {
+    let root = EnvRoot::load().ok()?;
+    let context = current_context_path(&root)?;
+
+    let account = context.account.as_deref()?;
+    // like in `rustshop get context`, namespace only makes sense with a cluster
+    let (cluster, namespace) = match context.cluster.as_deref() {
+        Some(cluster) => (cluster, context.namespace.as_deref().unwrap_or("default")),
+        None => ("", ""),
+    };
+    let text = format
+        .replace("{account}", account)
+        .replace("{cluster}", cluster)
+        .replace("{namespace}", namespace);
+    // `shop.yaml` is only needed for `{shop}` and colors; parse it at most once
+    let shop_yaml = if color || format.contains("{shop}") {
+        root.load_shop_yaml_opt().ok().flatten()
+    } else {
+        None
+    };
+    let text = if format.contains("{shop}") {
+        text.replace("{shop}", &shop_yaml.as_ref()?.shop.name)
+    } else {
+        text
+    };
+    let text = text.trim_end_matches(['/', ':']);
+
+    if !color {
+        return Some(text.to_owned());
+    }
+
+    // without a (valid) `shop.yaml` there's no color, but the text is still useful
+    let color = shop_yaml.as_ref().and_then(|shop| {
+        shop.accounts
+            .get(account)
+            .and_then(|account| account.color.as_deref())
+            .and_then(ansi_color_code)
+    });
+
+    Some(if let Some(code) = color {
+        format!(
+            "{}{text}{}",
+            shell.escape(&format!("\x1b[{code}m")),
+            shell.escape("\x1b[0m")
+        )
+    } else {
+        text.to_owned()
+    })
+}
+
+/// The context wrapped commands would use: the persisted one, with
+/// overrides from the environment applied
+fn current_context_path(root: &EnvRoot) -> Option<ContextYaml> {
+    Some(
+        root.load_context_yaml_opt()
+            .ok()?
+            .unwrap_or_default()
+            .with_overrides(ContextYaml::from_env()),
+    )
+}
+
+/// Convert color name (or 256-color palette index) to an ANSI SGR code
+fn ansi_color_code(name: &str) -> Option<String> {
+    const NAMES: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+
+    let name = name.trim().to_lowercase();
+    if let Some(i) = NAMES.iter().position(|n| *n == name) {
+        return Some(format!("{}", 30 + i));
+    }
+    if let Some(i) = name
+        .strip_prefix("bright-")
+        .and_then(|name| NAMES.iter().position(|n| *n == name))
+    {
+        return Some(format!("{}", 90 + i));
+    }
+    name.parse::<u8>().ok().map(|i| format!("38;5;{i}"))
+}
+
+impl PromptShell {
+    /// Mark non-printing sequences, so the shell computes prompt width correctly
+    fn escape(self, s: &str) -> String {
+        match self {
+            PromptShell::Plain => s.to_owned(),
+            PromptShell::Bash => format!("\\[{s}\\]"),
+            PromptShell::Zsh => format!("%{{{s}%}}"),
+        }
+    }
+}
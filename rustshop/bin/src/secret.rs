@@ -0,0 +1,166 @@
+//! Application secrets stored in AWS Secrets Manager
+//!
+//! Each secret `<name>` is stored as a JSON object of keys to values in the
+//! Secrets Manager secret `<shop>/<name>` of the account. Generated
+//! workloads read them through external-secrets (see `add_external_secret`
+//! in `common-res-gen`).
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    process::{Command, Stdio},
+};
+
+use derive_more::Display;
+use error_stack::{bail, Context, Result, ResultExt};
+use rustshop_env::Env;
+use tracing::{debug, warn};
+
+use crate::{aws_api::Aws, creds};
+
+#[derive(Debug, Display)]
+pub enum SecretError {
+    #[display(fmt = "Loading rustshop env failed")]
+    EnvFailure,
+    #[display(fmt = "IO Error")]
+    Io,
+    #[display(fmt = "AWS call failed")]
+    Aws,
+    #[display(fmt = "Secret does not exist: {}", name)]
+    NotFound { name: String },
+    #[display(fmt = "Secret key does not exist: {} {}", name, key)]
+    KeyNotFound { name: String, key: String },
+    #[display(fmt = "Secret is not a JSON object of strings: {}", name)]
+    Malformed { name: String },
+}
+
+impl Context for SecretError {}
+
+pub type SecretResult<T> = Result<T, SecretError>;
+
+type SecretKeys = BTreeMap<String, String>;
+
+/// Secrets Manager secret id of an application secret
+pub fn secret_id(env: &Env, name: &str) -> String {
+    format!("{}/{name}", env.shop_cfg().name)
+}
+
+fn aws_for_account(env: &Env, account: Option<String>) -> SecretResult<Aws> {
+    let account = if let Some(account) = account {
+        account
+    } else {
+        env.get_context_account()
+            .change_context(SecretError::EnvFailure)?
+            .account
+            .expect("get_context_account took care of it")
+            .0
+    };
+    let account_ref = env
+        .get_account_ref(&account)
+        .change_context(SecretError::EnvFailure)?;
+
+    let aws = Aws::new(
+        Some(account_ref.user.aws_profile.clone()),
+        account_ref.shop.bootstrap_aws_region.clone(),
+    );
+
+    Ok(match creds::get_or_refresh(env, &account) {
+        Ok(Some(creds)) => aws.with_creds(creds),
+        Ok(None) => aws,
+        Err(e) => {
+            warn!("Could not use cached credentials, falling back to `AWS_PROFILE`: {e:?}");
+            aws
+        }
+    })
+}
+
+fn load_keys(aws: &Aws, id: &str, name: &str) -> SecretResult<Option<SecretKeys>> {
+    let value = match aws.get_secret_string(id).change_context(SecretError::Aws)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    Ok(Some(serde_json::from_str(&value).change_context_lazy(
+        || SecretError::Malformed {
+            name: name.to_owned(),
+        },
+    )?))
+}
+
+/// Set `key` of secret `name`, with the value read from stdin
+pub fn set(env: &Env, account: Option<String>, name: &str, key: &str) -> SecretResult<()> {
+    let value = read_value(name, key)?;
+
+    let aws = aws_for_account(env, account)?;
+    let id = secret_id(env, name);
+
+    let existing = load_keys(&aws, &id, name)?;
+    let create = existing.is_none();
+    let mut keys = existing.unwrap_or_default();
+    keys.insert(key.to_owned(), value);
+
+    debug!(id, key, create, "Updating secret");
+    let json = serde_json::to_string(&keys).change_context(SecretError::Io)?;
+    aws.put_secret_string(&id, &json, create)
+        .change_context(SecretError::Aws)?;
+
+    Ok(())
+}
+
+/// Read the value from stdin, prompting (without echo) if it's a terminal
+fn read_value(name: &str, key: &str) -> SecretResult<String> {
+    let mut value = String::new();
+
+    if atty::is(atty::Stream::Stdin) {
+        eprint!("Value of {name} {key}: ");
+        let echo_off = set_echo(false);
+        let res = io::stdin().read_line(&mut value);
+        if echo_off {
+            set_echo(true);
+        }
+        eprintln!();
+        res.change_context(SecretError::Io)?;
+    } else {
+        io::stdin()
+            .read_to_string(&mut value)
+            .change_context(SecretError::Io)?;
+    }
+
+    Ok(value.trim_end_matches(['\n', '\r']).to_owned())
+}
+
+/// Turn terminal echo on/off; returns `false` if that failed
+fn set_echo(on: bool) -> bool {
+    Command::new("stty")
+        .arg(if on { "echo" } else { "-echo" })
+        .stdin(Stdio::inherit())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Get `key` of secret `name`, or all the keys (as JSON)
+pub fn get(
+    env: &Env,
+    account: Option<String>,
+    name: &str,
+    key: Option<&str>,
+) -> SecretResult<String> {
+    let aws = aws_for_account(env, account)?;
+    let id = secret_id(env, name);
+
+    let keys = load_keys(&aws, &id, name)?.ok_or_else(|| SecretError::NotFound {
+        name: name.to_owned(),
+    })?;
+
+    if let Some(key) = key {
+        match keys.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => bail!(SecretError::KeyNotFound {
+                name: name.to_owned(),
+                key: key.to_owned(),
+            }),
+        }
+    } else {
+        serde_json::to_string_pretty(&keys).change_context(SecretError::Io)
+    }
+}
@@ -8,8 +8,14 @@ use std::{
 
 use derive_more::Display;
 use error_stack::{Context, Result, ResultExt};
-use rustshop_env::{AccountCfg, Env, ShopAccountCfg, ShopClusterCfg};
-use tracing::{debug, info, trace};
+use rustshop_env::{AccountCfg, ContextYaml, Env, ShopAccountCfg, ShopClusterCfg};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    audit::{self, AuditEntry},
+    aws_api::Credentials,
+    creds,
+};
 
 #[derive(Debug, Display)]
 pub enum WrapError {
@@ -61,16 +67,29 @@ pub fn exec_wrapped_bin(bin: OsString, args: Vec<OsString>) -> WrapResult<()> {
         .get_context_account()
         .change_context(WrapError::EnvFailure)?;
 
-    let account_cfg = &context
+    let context_path = ContextYaml::from(context.clone());
+    let (account_name, account_cfg) = context
         .account
-        .expect("account set checked in get_context_account")
-        .1;
+        .expect("account set checked in get_context_account");
+    let account_cfg = &account_cfg;
+
+    let creds = match creds::get_or_refresh(&env, &account_name) {
+        Ok(creds) => creds,
+        Err(e) => {
+            warn!("Could not use cached credentials, falling back to `AWS_PROFILE`: {e:?}");
+            None
+        }
+    };
 
     trace!("Setting `aws` cli envs");
-    set_aws_envs_on(account_cfg, &mut cmd);
+    if let Some(creds) = creds.as_ref() {
+        set_aws_creds_envs_on(creds, &mut cmd);
+    } else {
+        set_aws_envs_on(account_cfg, &mut cmd);
+    }
     if bin_base_name.to_str() == Some("terraform") {
         trace!("Setting `terraform` envs");
-        set_tf_aws_envs_on(&env, account_cfg, &mut cmd)?;
+        set_tf_aws_envs_on(&env, account_cfg, creds.is_some(), &mut cmd)?;
     }
 
     if bin_base_name.to_str() == Some("kops") {
@@ -92,7 +111,8 @@ pub fn exec_wrapped_bin(bin: OsString, args: Vec<OsString>) -> WrapResult<()> {
 
     match bin_base_name.to_str() {
         // helm and kubectl have the similar CLI behavior and they tolerate multiple `--context` and `--namespace`
-        // arguments, with the following ones overrding the previous ones; so we can just add these as defaults
+        // arguments, with the following ones overrding the previous ones; but not all subcommands do,
+        // so we only add these as defaults when the user didn't specify them
         n @ Some("kubectl") | n @ Some("helm") => {
             let cfg = env.get_context().change_context(WrapError::EnvFailure)?;
 
@@ -105,22 +125,21 @@ pub fn exec_wrapped_bin(bin: OsString, args: Vec<OsString>) -> WrapResult<()> {
                 Err(new_cmd.exec()).change_context(WrapError::ExecFailed)?;
             }
 
-            if let Some(cluster) = cfg.cluster {
-                trace!("Adding `--context` to `kubectl`");
-                cmd.args([
-                    // well, actually helm named it differently
-                    if n == Some("helm") {
-                        "--kube-context"
-                    } else {
-                        "--context"
-                    },
-                    &cluster.1.user.kube_ctx,
-                ]);
-                if let Some(namespace) = cfg.namespace {
-                    trace!("Adding `--namespace` to `kubectl`");
-                    cmd.args(["--namespace", &namespace]);
-                }
-            }
+            let kube_args = if n == Some("helm") {
+                KubeArgs::parse_helm(&args)
+            } else {
+                KubeArgs::parse_kubectl(&args)
+            };
+
+            cmd.args(
+                kube_args.default_args(
+                    n == Some("helm"),
+                    cfg.cluster
+                        .as_ref()
+                        .map(|cluster| cluster.1.user.kube_ctx.as_str()),
+                    cfg.namespace.as_deref(),
+                ),
+            );
         }
         _ => {}
     }
@@ -168,12 +187,28 @@ pub fn exec_wrapped_bin(bin: OsString, args: Vec<OsString>) -> WrapResult<()> {
                 "-backend-config=dynamodb_table={}-bootstrap-terraform",
                 account_cfg.shop.bootstrap_name
             ),
-            &format!("-backend-config=profile={}", account_cfg.user.aws_profile),
             &format!(
                 "-backend-config=region={}",
                 account_cfg.shop.bootstrap_aws_region
             ),
         ]);
+
+        // with cached credentials, the backend should pick them up from the env
+        if creds.is_none() {
+            cmd.arg(format!(
+                "-backend-config=profile={}",
+                account_cfg.user.aws_profile
+            ));
+        }
+    }
+
+    if audit::is_recorded_by_parent() {
+        trace!("Already recorded by `rustshop exec`");
+    } else {
+        audit::record_or_warn(
+            &env,
+            AuditEntry::new(&context_path, &bin_base_name.to_string_lossy(), &args),
+        );
     }
 
     trace!("Exec: {cmd:?}");
@@ -214,6 +249,249 @@ fn is_kubectl_switch(base_bin: &OsStr, args: &[OsString]) -> bool {
             .unwrap_or(false)
 }
 
+/// `kubectl` subcommands that don't need (or don't accept) `--context`/`--namespace`
+const KUBECTL_CONTEXT_AGNOSTIC_SUBCOMMANDS: &[&str] = &[
+    "completion",
+    "config",
+    "help",
+    "kustomize",
+    "options",
+    "plugin",
+];
+
+/// Builtin `kubectl` subcommands; anything else is a plugin, and `kubectl`
+/// refuses flags placed before a plugin name
+const KUBECTL_BUILTIN_SUBCOMMANDS: &[&str] = &[
+    "alpha",
+    "annotate",
+    "api-resources",
+    "api-versions",
+    "apply",
+    "attach",
+    "auth",
+    "autoscale",
+    "certificate",
+    "cluster-info",
+    "completion",
+    "config",
+    "cordon",
+    "cp",
+    "create",
+    "debug",
+    "delete",
+    "describe",
+    "diff",
+    "drain",
+    "edit",
+    "events",
+    "exec",
+    "explain",
+    "expose",
+    "get",
+    "help",
+    "kustomize",
+    "label",
+    "logs",
+    "options",
+    "patch",
+    "plugin",
+    "port-forward",
+    "proxy",
+    "replace",
+    "rollout",
+    "run",
+    "scale",
+    "set",
+    "taint",
+    "top",
+    "uncordon",
+    "version",
+    "wait",
+];
+
+/// `kubectl` global flags taking a separate value (`--flag value`)
+const KUBECTL_VALUE_FLAGS: &[&str] = &[
+    "-n",
+    "--namespace",
+    "--context",
+    "--kubeconfig",
+    "--cluster",
+    "--user",
+    "-s",
+    "--server",
+    "--token",
+    "--as",
+    "--as-group",
+    "--as-uid",
+    "--cache-dir",
+    "--certificate-authority",
+    "--client-certificate",
+    "--client-key",
+    "--request-timeout",
+    "--tls-server-name",
+    "-v",
+    "--v",
+];
+
+/// `helm` subcommands that don't need (or don't accept) `--kube-context`/`--namespace`
+const HELM_CONTEXT_AGNOSTIC_SUBCOMMANDS: &[&str] = &[
+    "completion",
+    "create",
+    "dep",
+    "dependency",
+    "env",
+    "fetch",
+    "help",
+    "inspect",
+    "lint",
+    "package",
+    "plugin",
+    "pull",
+    "push",
+    "registry",
+    "repo",
+    "search",
+    "show",
+    "verify",
+    "version",
+];
+
+/// `helm` global flags taking a separate value (`--flag value`)
+const HELM_VALUE_FLAGS: &[&str] = &[
+    "-n",
+    "--namespace",
+    "--kube-context",
+    "--kubeconfig",
+    "--kube-apiserver",
+    "--kube-as-user",
+    "--kube-as-group",
+    "--kube-ca-file",
+    "--kube-token",
+    "--registry-config",
+    "--repository-cache",
+    "--repository-config",
+    "--burst-limit",
+];
+
+/// What the user already specified in `kubectl`/`helm` arguments
+#[derive(Debug, Default, PartialEq, Eq)]
+struct KubeArgs {
+    subcommand: Option<String>,
+    has_context: bool,
+    has_namespace: bool,
+    context_agnostic: bool,
+}
+
+impl KubeArgs {
+    fn parse_kubectl(args: &[OsString]) -> Self {
+        let mut parsed = Self::parse(args, KUBECTL_VALUE_FLAGS, &["--context", "--kubeconfig"]);
+        parsed.context_agnostic = match parsed.subcommand.as_deref() {
+            Some(subcommand) => {
+                KUBECTL_CONTEXT_AGNOSTIC_SUBCOMMANDS.contains(&subcommand)
+                    || !KUBECTL_BUILTIN_SUBCOMMANDS.contains(&subcommand)
+            }
+            None => false,
+        };
+        parsed
+    }
+
+    fn parse_helm(args: &[OsString]) -> Self {
+        let mut parsed = Self::parse(args, HELM_VALUE_FLAGS, &["--kube-context", "--kubeconfig"]);
+        parsed.context_agnostic = parsed
+            .subcommand
+            .as_deref()
+            .map(|subcommand| HELM_CONTEXT_AGNOSTIC_SUBCOMMANDS.contains(&subcommand))
+            .unwrap_or(false);
+        parsed
+    }
+
+    /// `--context`/`--namespace` to add in front of the user's arguments
+    ///
+    /// The namespace is only added together with the context: it belongs to
+    /// the rustshop cluster, so it would be wrong for a context (or
+    /// kubeconfig) the user selected explicitly.
+    fn default_args(
+        &self,
+        helm: bool,
+        kube_ctx: Option<&str>,
+        namespace: Option<&str>,
+    ) -> Vec<String> {
+        let kube_ctx = match kube_ctx {
+            Some(kube_ctx) => kube_ctx,
+            None => return vec![],
+        };
+        if self.context_agnostic {
+            trace!(
+                subcommand = self.subcommand,
+                "Not adding `--context` to a context-agnostic subcommand"
+            );
+            return vec![];
+        }
+        if self.has_context {
+            trace!("Not adding `--context`/`--namespace`, context already specified by the user");
+            return vec![];
+        }
+
+        trace!("Adding `--context`");
+        let mut args = vec![
+            // well, actually helm named it differently
+            if helm { "--kube-context" } else { "--context" }.to_owned(),
+            kube_ctx.to_owned(),
+        ];
+        match namespace {
+            _ if self.has_namespace => {
+                trace!("Not adding `--namespace`, already specified by the user")
+            }
+            Some(namespace) => {
+                trace!("Adding `--namespace`");
+                args.extend(["--namespace".to_owned(), namespace.to_owned()]);
+            }
+            None => {}
+        }
+        args
+    }
+
+    fn parse(args: &[OsString], value_flags: &[&str], context_flags: &[&str]) -> Self {
+        let mut parsed = Self::default();
+        let mut args = args.iter().filter_map(|arg| arg.to_str());
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            }
+
+            if !arg.starts_with('-') {
+                if parsed.subcommand.is_none() {
+                    parsed.subcommand = Some(arg.to_owned());
+                }
+                continue;
+            }
+
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, _value)) => (flag, true),
+                // `-nfoo` form
+                None if arg.starts_with("-n") && !arg.starts_with("--") && arg.len() > 2 => {
+                    ("-n", true)
+                }
+                None => (arg, false),
+            };
+
+            if context_flags.contains(&flag) {
+                parsed.has_context = true;
+            }
+            if ["-n", "--namespace", "-A", "--all-namespaces"].contains(&flag) {
+                parsed.has_namespace = true;
+            }
+            if !inline_value && value_flags.contains(&flag) {
+                // skip the value, so it's not mistaken for a subcommand
+                args.next();
+            }
+        }
+
+        parsed
+    }
+}
+
 /// Set the variables that `aws` CLI command expects (and other binaries too)
 pub fn set_aws_envs_on<'cmd>(
     account_cfg: &AccountCfg,
@@ -230,11 +508,33 @@ pub fn set_aws_envs_on<'cmd>(
     cmd
 }
 
+/// Set the credential variables that `aws` CLI command expects (and other binaries too)
+///
+/// Used instead of `set_aws_envs_on` when cached credentials are available.
+pub fn set_aws_creds_envs_on<'cmd>(
+    creds: &Credentials,
+    cmd: &'cmd mut Command,
+) -> &'cmd mut Command {
+    debug!(AWS_ACCESS_KEY_ID = creds.access_key_id, "Setting");
+    cmd.env("AWS_ACCESS_KEY_ID", &creds.access_key_id)
+        .env("AWS_SECRET_ACCESS_KEY", &creds.secret_access_key)
+        // profile would take precedence in some tools, and re-assume the role
+        .env_remove("AWS_PROFILE");
+    match creds.session_token.as_ref() {
+        Some(session_token) => cmd.env("AWS_SESSION_TOKEN", session_token),
+        None => cmd.env_remove("AWS_SESSION_TOKEN"),
+    }
+}
+
 /// Set the variables like for `aws` CLI, but prefixed with `TF_VAR_` so they
 /// are visible as Terraform variables.
+///
+/// With `cached_creds` the profile is not set (the variable defaults to
+/// empty), so the provider uses credentials from the environment.
 pub fn set_tf_aws_envs_on<'cmd>(
     env: &Env,
     account_cfg: &AccountCfg,
+    cached_creds: bool,
     cmd: &'cmd mut Command,
 ) -> WrapResult<&'cmd mut Command> {
     debug!(TF_VAR_SHOPNAME = env.shop_cfg().name, "Setting");
@@ -258,11 +558,12 @@ pub fn set_tf_aws_envs_on<'cmd>(
         &account_cfg.shop.bootstrap_aws_region,
     );
 
-    debug!(
-        TF_VAR_AWS_PROFILE = &account_cfg.user.aws_profile,
-        "Setting"
-    );
-    cmd.env("TF_VAR_AWS_PROFILE", &account_cfg.user.aws_profile);
+    if cached_creds {
+        cmd.env_remove("TF_VAR_AWS_PROFILE");
+    } else {
+        debug!(TF_VAR_AWS_PROFILE = account_cfg.user.aws_profile, "Setting");
+        cmd.env("TF_VAR_AWS_PROFILE", &account_cfg.user.aws_profile);
+    }
 
     debug!(
         TF_VAR_AWS_REGION = account_cfg.shop.bootstrap_aws_region,
@@ -292,3 +593,155 @@ pub fn set_kops_envs_on<'cmd>(
 pub fn get_kops_state_store_url(account_cfg: &ShopAccountCfg) -> String {
     format!("s3://{}-bootstrap-kops-state", account_cfg.bootstrap_name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<OsString> {
+        args.split_whitespace().map(OsString::from).collect()
+    }
+
+    fn kubectl(a: &str) -> KubeArgs {
+        KubeArgs::parse_kubectl(&args(a))
+    }
+
+    fn helm(a: &str) -> KubeArgs {
+        KubeArgs::parse_helm(&args(a))
+    }
+
+    #[test]
+    fn kubectl_namespace_forms() {
+        for a in [
+            "get pods -n foo",
+            "get pods -nfoo",
+            "get pods --namespace foo",
+            "get pods --namespace=foo",
+            "-n foo get pods",
+            "get pods -A",
+            "get pods --all-namespaces",
+        ] {
+            let parsed = kubectl(a);
+            assert!(parsed.has_namespace, "{a}");
+            assert!(!parsed.has_context, "{a}");
+            assert_eq!(parsed.subcommand.as_deref(), Some("get"), "{a}");
+        }
+        assert!(!kubectl("get pods").has_namespace);
+    }
+
+    #[test]
+    fn kubectl_context_forms() {
+        for a in [
+            "--context other get pods",
+            "get pods --context=other",
+            "--kubeconfig /tmp/kubeconfig get pods",
+            "get pods --kubeconfig=/tmp/kubeconfig",
+        ] {
+            let parsed = kubectl(a);
+            assert!(parsed.has_context, "{a}");
+            assert_eq!(parsed.subcommand.as_deref(), Some("get"), "{a}");
+        }
+    }
+
+    #[test]
+    fn kubectl_value_flags_before_subcommand() {
+        assert_eq!(
+            kubectl("--kubeconfig x get pods").subcommand.as_deref(),
+            Some("get")
+        );
+        assert_eq!(
+            kubectl("-v 6 --request-timeout 5s get pods")
+                .subcommand
+                .as_deref(),
+            Some("get")
+        );
+    }
+
+    #[test]
+    fn kubectl_args_after_double_dash_are_ignored() {
+        let parsed = kubectl("exec mypod -- ls -n foo --context bar");
+        assert_eq!(parsed.subcommand.as_deref(), Some("exec"));
+        assert!(!parsed.has_namespace);
+        assert!(!parsed.has_context);
+        assert!(!parsed.context_agnostic);
+    }
+
+    #[test]
+    fn kubectl_context_agnostic_subcommands() {
+        assert!(kubectl("config view").context_agnostic);
+        assert!(kubectl("config use-context foo").context_agnostic);
+        // plugins might not accept the global flags
+        assert!(kubectl("krew list").context_agnostic);
+        assert!(!kubectl("get pods").context_agnostic);
+        assert!(!kubectl("").context_agnostic);
+    }
+
+    #[test]
+    fn helm_forms() {
+        let parsed = helm("--kube-context other -n foo list");
+        assert_eq!(parsed.subcommand.as_deref(), Some("list"));
+        assert!(parsed.has_context);
+        assert!(parsed.has_namespace);
+
+        // `--context` is not a helm flag
+        assert!(!helm("list --context other").has_context);
+        assert!(helm("repo add x y").context_agnostic);
+        assert!(!helm("upgrade --install x y").context_agnostic);
+    }
+
+    fn default_args(parsed: KubeArgs, helm: bool, namespace: Option<&str>) -> String {
+        parsed
+            .default_args(helm, Some("prod-ctx"), namespace)
+            .join(" ")
+    }
+
+    #[test]
+    fn adds_context_and_namespace() {
+        assert_eq!(
+            default_args(kubectl("get pods"), false, Some("foo")),
+            "--context prod-ctx --namespace foo"
+        );
+        assert_eq!(
+            default_args(helm("list"), true, Some("foo")),
+            "--kube-context prod-ctx --namespace foo"
+        );
+        assert_eq!(
+            default_args(kubectl("get pods"), false, None),
+            "--context prod-ctx"
+        );
+        assert!(kubectl("get pods")
+            .default_args(false, None, Some("foo"))
+            .is_empty());
+    }
+
+    #[test]
+    fn keeps_user_namespace() {
+        assert_eq!(
+            default_args(kubectl("get pods -nbar"), false, Some("foo")),
+            "--context prod-ctx"
+        );
+        assert_eq!(
+            default_args(kubectl("get pods -A"), false, Some("foo")),
+            "--context prod-ctx"
+        );
+    }
+
+    #[test]
+    fn no_namespace_for_user_selected_context() {
+        // the namespace belongs to the rustshop cluster, not to the selected one
+        assert_eq!(
+            default_args(kubectl("--context other get pods"), false, Some("foo")),
+            ""
+        );
+        assert_eq!(
+            default_args(kubectl("--kubeconfig x get pods"), false, Some("foo")),
+            ""
+        );
+    }
+
+    #[test]
+    fn nothing_for_context_agnostic_subcommands() {
+        assert_eq!(default_args(kubectl("config view"), false, Some("foo")), "");
+        assert_eq!(default_args(helm("repo add x y"), true, Some("foo")), "");
+    }
+}
@@ -25,6 +25,10 @@ pub struct ShopAccountCfg {
     pub bootstrap_aws_region: String,
 
     pub clusters: BTreeMap<ClusterName, ShopClusterCfg>,
+
+    /// Color to display the account with (e.g. `red` for prod), see `rustshop prompt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -83,6 +87,9 @@ pub struct ShopYaml {
     #[serde(flatten)]
     pub shop: ShopCfg,
     pub accounts: BTreeMap<AccountName, ShopAccountCfg>,
+    /// Shell command receiving each audit log entry (JSON) on stdin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_hook: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
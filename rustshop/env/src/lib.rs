@@ -55,6 +55,12 @@ pub enum EnvError {
     InconsistentClusterData { name: String },
     #[display(fmt = "Cluster user data missing: {}", name)]
     ClusterNotConfigured { name: String },
+
+    #[display(
+        fmt = "Context is overridden with {} (e.g. in `rustshop shell`), can't switch",
+        "vars.join(\", \")"
+    )]
+    ContextOverridden { vars: Vec<&'static str> },
 }
 
 pub type EnvResult<T> = Result<T, EnvError>;
@@ -105,6 +111,7 @@ impl EnvRoot {
         let shop_yaml = ShopYaml {
             shop,
             accounts: BTreeMap::new(),
+            audit_hook: None,
         };
 
         if let Some(_shop_yaml) = self.load_shop_yaml_opt()? {
@@ -137,11 +144,16 @@ impl EnvRoot {
         self.path.join(Self::ROOT_SUBDIR).join("user.yaml")
     }
 
+    /// Directory for local, user-specific state (not meant to be committed)
+    pub fn state_dir(&self) -> PathBuf {
+        self.root_cfg_dir().join("state")
+    }
+
     pub fn context_yaml_path(&self) -> PathBuf {
-        self.root_cfg_dir().join("state").join("context.yaml")
+        self.state_dir().join("context.yaml")
     }
 
-    fn load_shop_yaml_opt(&self) -> EnvResult<Option<ShopYaml>> {
+    pub fn load_shop_yaml_opt(&self) -> EnvResult<Option<ShopYaml>> {
         let path = self.shop_yaml_path();
         if !path.exists() {
             return Ok(None);
@@ -181,7 +193,8 @@ impl EnvRoot {
         })
     }
 
-    fn load_context_yaml_opt(&self) -> EnvResult<Option<ContextYaml>> {
+    /// Load persisted context as is, without any validation
+    pub fn load_context_yaml_opt(&self) -> EnvResult<Option<ContextYaml>> {
         let path = self.context_yaml_path();
         if !path.exists() {
             return Ok(None);
@@ -404,6 +417,7 @@ impl Env {
             bootstrap_name: format!("{}-{}", self.shop.shop.name, name),
             bootstrap_aws_region: aws_region.to_string(),
             clusters: BTreeMap::new(),
+            color: None,
         };
         self.shop.accounts.insert(name.to_owned(), shop_cfg.clone());
         self.shop_dirty = true;
@@ -596,6 +610,7 @@ impl Env {
     }
 
     pub fn switch_account(&mut self, name: &str) -> EnvResult<EnvContext> {
+        ContextYaml::ensure_not_overridden()?;
         let context_path = self.normalize_context_path(self.load_context_yaml_opt()?, true)?;
 
         let context = self.resolve_context_path(&ContextYaml {
@@ -611,6 +626,7 @@ impl Env {
     }
 
     pub fn switch_cluster(&mut self, name: &str) -> EnvResult<EnvContext> {
+        ContextYaml::ensure_not_overridden()?;
         let context_path = self.normalize_context_path(self.load_context_yaml_opt()?, true)?;
 
         let context = self.resolve_context_path(&ContextYaml {
@@ -627,6 +643,7 @@ impl Env {
     }
 
     pub fn switch_namespace(&mut self, name: &str) -> EnvResult<EnvContext> {
+        ContextYaml::ensure_not_overridden()?;
         let context_path = self.normalize_context_path(self.load_context_yaml_opt()?, true)?;
 
         let context = self.resolve_context_path(&ContextYaml {
@@ -642,10 +659,40 @@ impl Env {
         Ok(context)
     }
 
+    /// Get the current context
+    ///
+    /// Persisted context can be overridden with `RUSTSHOP_ACCOUNT`, `RUSTSHOP_CLUSTER`
+    /// and `RUSTSHOP_NAMESPACE` env vars (see `rustshop exec`).
     pub fn get_context(&self) -> EnvResult<EnvContext> {
-        let context_path = self.normalize_context_path(self.load_context_yaml_opt()?, true)?;
+        self.get_context_with_overrides(ContextYaml::from_env())
+    }
+
+    /// Like `get_context`, but with parts of the context explicitly selected
+    ///
+    /// Selecting a different account drops the persisted cluster and namespace,
+    /// as they belong to a different account. Explicitly selected elements
+    /// must exist.
+    pub fn get_context_with_overrides(&self, overrides: ContextYaml) -> EnvResult<EnvContext> {
+        let context_path = self
+            .load_context_yaml_opt()?
+            .unwrap_or_default()
+            .with_overrides(overrides.clone());
+
+        let context =
+            self.resolve_context_path(&self.normalize_context_path(Some(context_path), true)?)?;
+
+        if let Some(account) = overrides.account {
+            if context.account.as_ref().map(|a| &a.0) != Some(&account) {
+                bail!(EnvError::AccountDoesNotExist { name: account });
+            }
+        }
+        if let Some(cluster) = overrides.cluster {
+            if context.cluster.as_ref().map(|c| &c.0) != Some(&cluster) {
+                bail!(EnvError::ClusterDoesNotExist { name: cluster });
+            }
+        }
 
-        self.resolve_context_path(&context_path)
+        Ok(context)
     }
 
     /// Like `get_context`, but will error out if account not set
@@ -707,6 +754,10 @@ impl Env {
     pub fn shop_cfg(&self) -> &ShopCfg {
         &self.shop.shop
     }
+
+    pub fn audit_hook(&self) -> Option<&str> {
+        self.shop.audit_hook.as_deref()
+    }
 }
 
 impl<'env> From<EnvAccountMut<'env>> for AccountCfg {
@@ -752,6 +803,66 @@ pub struct EnvContext {
     pub namespace: Option<String>,
 }
 
+impl ContextYaml {
+    pub const ACCOUNT_ENV_NAME: &'static str = "RUSTSHOP_ACCOUNT";
+    pub const CLUSTER_ENV_NAME: &'static str = "RUSTSHOP_CLUSTER";
+    pub const NAMESPACE_ENV_NAME: &'static str = "RUSTSHOP_NAMESPACE";
+
+    /// Context path overrides set in the environment (empty values are ignored)
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|val| !val.is_empty());
+        ContextYaml {
+            account: var(Self::ACCOUNT_ENV_NAME),
+            cluster: var(Self::CLUSTER_ENV_NAME),
+            namespace: var(Self::NAMESPACE_ENV_NAME),
+        }
+    }
+
+    /// Apply `overrides` to this (persisted) context path
+    ///
+    /// Selecting a different account drops the persisted cluster and namespace,
+    /// and a different cluster the persisted namespace, as they belong to
+    /// something else.
+    pub fn with_overrides(self, overrides: ContextYaml) -> ContextYaml {
+        if overrides.account.is_some() && overrides.account != self.account {
+            overrides
+        } else if overrides.cluster.is_some() && overrides.cluster != self.cluster {
+            ContextYaml {
+                account: self.account,
+                ..overrides
+            }
+        } else {
+            ContextYaml {
+                account: self.account,
+                cluster: self.cluster,
+                namespace: overrides.namespace.or(self.namespace),
+            }
+        }
+    }
+
+    /// Fail if any part of the context is overridden in the environment
+    ///
+    /// Switching would only change the persisted context, while the
+    /// overridden one stays in effect.
+    pub fn ensure_not_overridden() -> EnvResult<()> {
+        let overrides = Self::from_env();
+        let vars: Vec<_> = [
+            (Self::ACCOUNT_ENV_NAME, overrides.account.is_some()),
+            (Self::CLUSTER_ENV_NAME, overrides.cluster.is_some()),
+            (Self::NAMESPACE_ENV_NAME, overrides.namespace.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, is_set)| *is_set)
+        .map(|(name, _)| name)
+        .collect();
+
+        if !vars.is_empty() {
+            bail!(EnvError::ContextOverridden { vars });
+        }
+        Ok(())
+    }
+}
+
 impl From<EnvContext> for ContextYaml {
     fn from(val: EnvContext) -> Self {
         ContextYaml {
@@ -1072,3 +1183,188 @@ impl Env {
 }
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHOP_YAML: &str = "
+name: shop
+domain: shop.com
+accounts:
+  prod:
+    bootstrap_name: shop-prod
+    bootstrap_aws_region: us-east-1
+    clusters:
+      prod:
+        domain: prod.k8s.shop.com
+  root:
+    bootstrap_name: shop-root
+    bootstrap_aws_region: us-east-1
+    clusters:
+      root:
+        domain: root.k8s.shop.com
+";
+
+    const USER_YAML: &str = "
+accounts:
+  prod:
+    aws_profile: shop-prod
+    prod:
+      kube_ctx: prod-ctx
+  root:
+    aws_profile: shop-root
+    root:
+      kube_ctx: root-ctx
+";
+
+    fn path(account: Option<&str>, cluster: Option<&str>, namespace: Option<&str>) -> ContextYaml {
+        ContextYaml {
+            account: account.map(ToOwned::to_owned),
+            cluster: cluster.map(ToOwned::to_owned),
+            namespace: namespace.map(ToOwned::to_owned),
+        }
+    }
+
+    fn parts(context: &ContextYaml) -> (Option<&str>, Option<&str>, Option<&str>) {
+        (
+            context.account.as_deref(),
+            context.cluster.as_deref(),
+            context.namespace.as_deref(),
+        )
+    }
+
+    /// Env in a temporary root, with `prod/prod:apps` as the persisted context
+    fn test_env() -> (tempfile::TempDir, Env) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = EnvRoot {
+            path: dir.path().to_owned(),
+        };
+        std::fs::create_dir_all(root.root_cfg_dir()).unwrap();
+        std::fs::write(root.shop_yaml_path(), SHOP_YAML).unwrap();
+        std::fs::write(root.user_yaml_path(), USER_YAML).unwrap();
+        root.write_context_yaml(&path(Some("prod"), Some("prod"), Some("apps")))
+            .unwrap();
+
+        let env = Env {
+            shop: root.load_shop_yaml().unwrap(),
+            user: root.load_user_yaml_opt().unwrap().unwrap_or_default(),
+            context_path: root.load_context_yaml_opt().unwrap().unwrap_or_default(),
+            shop_dirty: false,
+            user_dirty: false,
+            context_dirty: false,
+            root,
+        };
+        (dir, env)
+    }
+
+    #[test]
+    fn overrides_replace_what_they_select() {
+        let persisted = || path(Some("prod"), Some("prod"), Some("apps"));
+
+        let context = persisted().with_overrides(ContextYaml::default());
+        assert_eq!(parts(&context), (Some("prod"), Some("prod"), Some("apps")));
+
+        let context = persisted().with_overrides(path(None, None, Some("jobs")));
+        assert_eq!(parts(&context), (Some("prod"), Some("prod"), Some("jobs")));
+
+        // same account (or cluster) keeps the rest of the persisted context
+        let context = persisted().with_overrides(path(Some("prod"), Some("prod"), None));
+        assert_eq!(parts(&context), (Some("prod"), Some("prod"), Some("apps")));
+    }
+
+    #[test]
+    fn overrides_drop_what_belongs_to_something_else() {
+        let persisted = || path(Some("prod"), Some("prod"), Some("apps"));
+
+        let context = persisted().with_overrides(path(Some("root"), None, None));
+        assert_eq!(parts(&context), (Some("root"), None, None));
+
+        let context = persisted().with_overrides(path(None, Some("staging"), None));
+        assert_eq!(parts(&context), (Some("prod"), Some("staging"), None));
+    }
+
+    #[test]
+    fn context_with_overrides() {
+        let (_dir, env) = test_env();
+
+        let context = env
+            .get_context_with_overrides(ContextYaml::default())
+            .unwrap();
+        assert_eq!(
+            parts(&context.into()),
+            (Some("prod"), Some("prod"), Some("apps"))
+        );
+
+        let context = env
+            .get_context_with_overrides(path(None, None, Some("jobs")))
+            .unwrap();
+        assert_eq!(
+            parts(&context.into()),
+            (Some("prod"), Some("prod"), Some("jobs"))
+        );
+
+        let context = env
+            .get_context_with_overrides(path(Some("root"), Some("root"), None))
+            .unwrap();
+        assert_eq!(
+            context.cluster.map(|(_, cfg)| cfg.user.kube_ctx),
+            Some("root-ctx".to_owned())
+        );
+    }
+
+    #[test]
+    fn context_overrides_must_exist() {
+        let (_dir, env) = test_env();
+
+        let err = env
+            .get_context_with_overrides(path(Some("staging"), None, None))
+            .unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            EnvError::AccountDoesNotExist { name } if name == "staging"
+        ));
+
+        let err = env
+            .get_context_with_overrides(path(None, Some("root"), None))
+            .unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            EnvError::ClusterDoesNotExist { name } if name == "root"
+        ));
+    }
+
+    // the only test touching the process environment
+    #[test]
+    fn overrides_from_env() {
+        for name in [
+            ContextYaml::ACCOUNT_ENV_NAME,
+            ContextYaml::CLUSTER_ENV_NAME,
+            ContextYaml::NAMESPACE_ENV_NAME,
+        ] {
+            std::env::remove_var(name);
+        }
+        assert!(ContextYaml::ensure_not_overridden().is_ok());
+
+        // empty values don't count
+        std::env::set_var(ContextYaml::ACCOUNT_ENV_NAME, "");
+        std::env::set_var(ContextYaml::NAMESPACE_ENV_NAME, "jobs");
+        assert_eq!(parts(&ContextYaml::from_env()), (None, None, Some("jobs")));
+
+        std::env::set_var(ContextYaml::CLUSTER_ENV_NAME, "prod");
+        let err = ContextYaml::ensure_not_overridden().unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            EnvError::ContextOverridden { vars }
+                if *vars == [ContextYaml::CLUSTER_ENV_NAME, ContextYaml::NAMESPACE_ENV_NAME]
+        ));
+
+        for name in [
+            ContextYaml::ACCOUNT_ENV_NAME,
+            ContextYaml::CLUSTER_ENV_NAME,
+            ContextYaml::NAMESPACE_ENV_NAME,
+        ] {
+            std::env::remove_var(name);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Admin listener (`--admin-listen`)
+//!
+//! Serves health checks and metrics, plus debug endpoints that must never be
+//! reachable through the ingress:
+//!
+//! * `GET /debug/config` - effective options, with secrets redacted
+//! * `GET|PUT /debug/log-level` - current/new `RUST_LOG`-style filter
+//! * `GET /debug/pprof/profile?seconds=N` - CPU profile in pprof format
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use common_tracing::LogFilterHandle;
+use pprof::protos::Message;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{health::Health, metrics::Metrics};
+
+/// Sampling frequency of CPU profiles
+const PROFILE_FREQUENCY_HZ: i32 = 99;
+const MAX_PROFILE_DURATION: Duration = Duration::from_secs(300);
+
+pub(crate) struct Admin {
+    config: serde_json::Value,
+    log_filter: Option<LogFilterHandle>,
+    /// Only one profile can be collected at a time
+    profiling: Mutex<()>,
+}
+
+impl Admin {
+    pub(crate) fn new(config: serde_json::Value, log_filter: Option<LogFilterHandle>) -> Self {
+        Self {
+            config,
+            log_filter,
+            profiling: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn router(self: Arc<Self>, health: Arc<Health>, metrics: &Metrics) -> Router {
+        Router::new()
+            .route("/debug/config", get(config_handler))
+            .route(
+                "/debug/log-level",
+                get(get_log_level_handler).put(set_log_level_handler),
+            )
+            .route("/debug/pprof/profile", get(profile_handler))
+            .layer(Extension(self))
+            .merge(health.router())
+            .merge(metrics.router())
+    }
+}
+
+async fn config_handler(Extension(admin): Extension<Arc<Admin>>) -> Json<serde_json::Value> {
+    Json(admin.config.clone())
+}
+
+async fn get_log_level_handler(Extension(admin): Extension<Arc<Admin>>) -> Response {
+    match admin.log_filter.as_ref() {
+        Some(log_filter) => format!("{}\n", log_filter.get()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn set_log_level_handler(
+    Extension(admin): Extension<Arc<Admin>>,
+    directives: String,
+) -> Response {
+    let log_filter = match admin.log_filter.as_ref() {
+        Some(log_filter) => log_filter,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let directives = directives.trim();
+    match log_filter.set(directives) {
+        Ok(()) => {
+            info!(directives, "Log filter changed");
+            format!("{directives}\n").into_response()
+        }
+        Err(e) => {
+            warn!(directives, "Invalid log filter: {e:?}");
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid log filter: {directives}\n"),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProfileParams {
+    seconds: Option<u64>,
+}
+
+async fn profile_handler(
+    Extension(admin): Extension<Arc<Admin>>,
+    Query(params): Query<ProfileParams>,
+) -> Response {
+    let duration = Duration::from_secs(params.seconds.unwrap_or(30)).min(MAX_PROFILE_DURATION);
+
+    let _profiling = match admin.profiling.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => return (StatusCode::CONFLICT, "Profiling already in progress\n").into_response(),
+    };
+
+    match collect_profile(duration).await {
+        Ok(profile) => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"profile.pb\"",
+                ),
+            ],
+            profile,
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("Profiling failed: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}\n")).into_response()
+        }
+    }
+}
+
+async fn collect_profile(duration: Duration) -> Result<Vec<u8>, String> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(PROFILE_FREQUENCY_HZ)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    tokio::time::sleep(duration).await;
+
+    let profile = guard
+        .report()
+        .build()
+        .and_then(|report| report.pprof())
+        .map_err(|e| e.to_string())?;
+    profile.write_to_bytes().map_err(|e| e.to_string())
+}
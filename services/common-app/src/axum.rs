@@ -1,68 +1,384 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{sync::Arc, time::Duration};
 
-use axum::{
-    error_handling::HandleErrorLayer,
-    http::{Request, StatusCode},
-    BoxError, Router,
-};
+use axum::{error_handling::HandleErrorLayer, http::Request, middleware, BoxError, Router};
 use error_stack::{IntoReport, ResultExt};
-use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
-use tracing::{debug, info, Span};
+use tokio::{sync::oneshot, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tower::{limit::GlobalConcurrencyLimitLayer, load_shed::LoadShedLayer, ServiceBuilder};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{error, info, info_span, Span};
 
 mod error;
-pub use self::error::*;
+mod limit;
+mod serve;
+mod webhook;
+pub use self::{
+    error::*,
+    limit::RouteLimits,
+    webhook::{GitHub, GitLab, Hmac, Stripe, VerifiedWebhook, WebhookProvider},
+};
+pub(crate) use self::{limit::Limits, serve::Listener};
 
-use crate::{opts, shutdown_signal, AppBuilder, AppError, AppResult};
+use crate::{
+    admin::Admin, health::Health, opts, shutdown_signal, tasks::RunningTasks, AppBuilder, AppError,
+    AppResult,
+};
 
 impl AppBuilder<opts::CommonOpts> {
     pub async fn run_axum(&self, func: impl FnOnce(Router) -> AppResult<Router>) -> AppResult<()> {
         let router = Router::new();
 
         let router = func(router)?;
-        let router = configure_axum_router(router);
-
-        // run it
-        let addr = SocketAddr::from(([0, 0, 0, 0], self.common_opts.listen_port));
-        info!("listening on {}", addr);
-        axum::Server::bind(&addr)
-            .serve(router.into_make_service())
-            .with_graceful_shutdown(shutdown_signal())
+        let limits = Limits::new(&self.common_opts.limits, self.route_limits.clone());
+        let router = configure_axum_router(router, limits, &self.common_opts.limits);
+
+        let metrics = self.metrics.clone();
+        let router = router.route_layer(middleware::from_fn(move |req, next| {
+            metrics.clone().track(req, next)
+        }));
+
+        let health = Arc::new(Health::new(self.readiness_checks.clone()));
+        let grace_period = Duration::from_secs(self.common_opts.shutdown_grace_period_secs);
+
+        // bound before anything starts, so e.g. a taken port fails right away
+        let admin_listener = self
+            .common_opts
+            .admin_listen
+            .map(Listener::bind_tcp)
+            .transpose()?;
+
+        // requested by the signal, or a failing background task or admin listener
+        let shutdown = CancellationToken::new();
+        let tasks = RunningTasks::start(&self.tasks, shutdown.clone());
+
+        let (router, admin_server) = match admin_listener {
+            Some(admin_listener) => {
+                let admin = Arc::new(Admin::new(
+                    self.config.clone(),
+                    self.tracing_guard
+                        .as_ref()
+                        .map(|guard| guard.log_filter().clone()),
+                ));
+                let admin_router = add_request_layers(admin.router(health.clone(), &self.metrics));
+
+                // the admin listener keeps running until the main one is done
+                let (stop_tx, stop_rx) = oneshot::channel::<()>();
+                let admin_server = tokio::spawn({
+                    let shutdown = shutdown.clone();
+                    async move {
+                        let res = serve::serve(
+                            admin_router,
+                            admin_listener,
+                            async move {
+                                let _ = stop_rx.await;
+                            },
+                            grace_period,
+                        )
+                        .await;
+                        if res.is_err() {
+                            error!("admin listener failed, shutting down");
+                            shutdown.cancel();
+                        }
+                        res
+                    }
+                });
+                (router, Some((stop_tx, admin_server)))
+            }
+            None => (
+                router
+                    .merge(health.clone().router())
+                    .merge(self.metrics.router()),
+                None,
+            ),
+        };
+        let router = add_request_layers(router);
+
+        let shutdown_delay = Duration::from_secs(self.common_opts.shutdown_delay_secs);
+
+        // in-flight requests and then tasks share the grace period
+        let (deadline_tx, deadline_rx) = oneshot::channel();
+        let res = serve::serve(
+            router,
+            Listener::from_opts(&self.common_opts),
+            async move {
+                tokio::select! {
+                    _ = shutdown_signal() => {}
+                    _ = shutdown.cancelled() => {}
+                }
+                info!("starting graceful shutdown");
+                health.set_shutting_down();
+                if !shutdown_delay.is_zero() {
+                    info!(
+                        "not ready, waiting {}s before closing the listener",
+                        shutdown_delay.as_secs()
+                    );
+                    tokio::time::sleep(shutdown_delay).await;
+                }
+                let _ = deadline_tx.send(Instant::now() + grace_period);
+            },
+            grace_period,
+        )
+        .await;
+
+        let deadline = deadline_rx
             .await
-            .report()
-            .change_context(AppError)?;
+            .unwrap_or_else(|_| Instant::now() + grace_period);
+        let tasks_res = tasks.shutdown(deadline).await;
 
-        Ok(())
+        if let Some((stop_tx, admin_server)) = admin_server {
+            let _ = stop_tx.send(());
+            admin_server.await.report().change_context(AppError)??;
+        }
+        info!("shutdown complete");
+
+        res.and(tasks_res)
     }
 }
 
-async fn handle_timeout_error(err: BoxError) -> (StatusCode, String) {
-    if err.is::<tower::timeout::error::Elapsed>() {
-        (
-            StatusCode::REQUEST_TIMEOUT,
-            "Request took too long".to_string(),
-        )
+/// Header carrying the request ID; generated if the client did not send one
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Span covering the whole request, continuing the caller's trace (if any)
+fn request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id,
+    );
+    common_tracing::set_remote_parent(&span, request.headers());
+    span
+}
+
+async fn handle_overload_error(err: BoxError) -> RequestError {
+    if err.is::<tower::load_shed::error::Overloaded>() {
+        RequestError::Overloaded
     } else {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Unhandled internal error: {}", err),
-        )
+        error!("Unhandled internal error: {err}");
+        RequestError::InternalError
     }
 }
 
-pub(crate) fn configure_axum_router(router: axum::Router) -> axum::Router {
+/// Apply limits to the app routes
+pub(crate) fn configure_axum_router(
+    router: axum::Router,
+    limits: Limits,
+    opts: &opts::LimitOpts,
+) -> axum::Router {
+    let limits = Arc::new(limits);
     router
         .layer(
             ServiceBuilder::new()
-                // `timeout` will produce an error if the handler takes
-                // too long so we must handle those
-                .layer(HandleErrorLayer::new(handle_timeout_error))
-                .timeout(Duration::from_secs(30)),
+                // load shedding rejects requests with an error, so we must handle those
+                .layer(HandleErrorLayer::new(handle_overload_error))
+                .option_layer(opts.load_shed.then(LoadShedLayer::new))
+                // `Router::layer` wraps every route separately; the global variant
+                // shares the limit between all of them
+                .option_layer(opts.concurrency_limit.map(GlobalConcurrencyLimitLayer::new)),
         )
-        .layer(TraceLayer::new_for_http())
+        .layer(middleware::from_fn(move |req, next| {
+            limits.clone().enforce(req, next)
+        }))
+}
+
+/// Tracing and request IDs for all the requests
+fn add_request_layers(router: axum::Router) -> axum::Router {
+    router
+        .layer(middleware::from_fn(add_problem_request_id))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
         .layer(
-            TraceLayer::new_for_http().on_request(|request: &Request<_>, _span: &Span| {
-                debug!("{:?}", request);
-            }),
+            ServiceBuilder::new()
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                .layer(PropagateRequestIdLayer::x_request_id()),
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::routing::get;
+    use clap::Parser;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::Notify,
+        task::JoinHandle,
+    };
+
+    use super::*;
+    use crate::{metrics::Metrics, opts::Opts, NoOpts};
+
+    type Events = Arc<Mutex<Vec<&'static str>>>;
+
+    struct TestApp {
+        addr: SocketAddr,
+        events: Events,
+        server: JoinHandle<AppResult<()>>,
+    }
+
+    /// Run an app with a `/slow` route and a background task, on a free local port
+    ///
+    /// As soon as `/slow` is in flight, another task fails, which shuts the
+    /// app down like the signal would. `events` records the order of things.
+    fn start(grace_period_secs: u64, request_time: Duration, task_ignores_cancel: bool) -> TestApp {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let opts = Opts::<NoOpts>::try_parse_from([
+            "app".to_owned(),
+            "--bind=127.0.0.1".to_owned(),
+            format!("--listen={}", addr.port()),
+            format!("--shutdown-grace-period={grace_period_secs}"),
+        ])
+        .unwrap();
+
+        let events = Events::default();
+        let in_flight = Arc::new(Notify::new());
+        let app = AppBuilder {
+            common_opts: opts.common_opts,
+            readiness_checks: vec![],
+            tasks: vec![],
+            route_limits: Default::default(),
+            metrics: Metrics::new(),
+            tracing_guard: None,
+            config: serde_json::Value::Null,
+            #[cfg(feature = "db")]
+            migrator: None,
+        }
+        .spawn_task("worker", {
+            let events = events.clone();
+            move |cancel| {
+                let events = events.clone();
+                async move {
+                    cancel.cancelled().await;
+                    events.lock().unwrap().push("task cancelled");
+                    if task_ignores_cancel {
+                        std::future::pending::<()>().await;
+                    }
+                    Ok(())
+                }
+            }
+        })
+        .spawn_task("shutdown", {
+            let in_flight = in_flight.clone();
+            move |_cancel| {
+                let in_flight = in_flight.clone();
+                async move {
+                    in_flight.notified().await;
+                    Err(error_stack::Report::new(AppError).attach_printable("shutting down"))
+                }
+            }
+        });
+
+        let server = tokio::spawn({
+            let events = events.clone();
+            async move {
+                app.run_axum(move |router| {
+                    Ok(router.route(
+                        "/slow",
+                        get(move || {
+                            let events = events.clone();
+                            let in_flight = in_flight.clone();
+                            async move {
+                                events.lock().unwrap().push("request started");
+                                in_flight.notify_one();
+                                tokio::time::sleep(request_time).await;
+                                events.lock().unwrap().push("request done");
+                                "done"
+                            }
+                        }),
+                    ))
+                })
+                .await
+            }
+        });
+
+        TestApp {
+            addr,
+            events,
+            server,
+        }
+    }
+
+    /// Status line of the response, `None` if the request could not be made
+    async fn request(addr: SocketAddr, path: &str) -> Option<String> {
+        let mut stream = TcpStream::connect(addr).await.ok()?;
+        let request = format!("GET {path} HTTP/1.1\r\nHost: app\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.ok()?;
+        response.lines().next().map(ToOwned::to_owned)
+    }
+
+    /// Like `request`, but waits for the app to start listening
+    fn spawn_request(addr: SocketAddr, path: &'static str) -> JoinHandle<Option<String>> {
+        tokio::spawn(async move {
+            for _ in 0..100 {
+                if TcpStream::connect(addr).await.is_ok() {
+                    return request(addr, path).await;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            None
+        })
+    }
+
+    fn events(events: &Events) -> Vec<&'static str> {
+        events.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_requests_then_cancels_tasks() {
+        let app = start(10, Duration::from_millis(500), false);
+
+        let slow = spawn_request(app.addr, "/slow");
+        while events(&app.events).is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // not accepting new connections, but the in-flight request goes on
+        let late = tokio::spawn(request(app.addr, "/healthz"));
+        assert_eq!(slow.await.unwrap().as_deref(), Some("HTTP/1.1 200 OK"));
+        assert_eq!(late.await.unwrap(), None);
+
+        // shut down by the failing task
+        assert!(app.server.await.unwrap().is_err());
+        assert_eq!(
+            events(&app.events),
+            ["request started", "request done", "task cancelled"]
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_and_tasks_share_the_grace_period() {
+        let app = start(1, Duration::from_secs(60), true);
+
+        let start = Instant::now();
+        let _slow = spawn_request(app.addr, "/slow");
+        let res = tokio::time::timeout(Duration::from_secs(10), app.server)
+            .await
+            .expect("shut down after the grace period")
+            .unwrap();
+
+        // one grace period for both the request and the task, not one each
+        assert!(start.elapsed() < Duration::from_millis(1800));
+        assert!(res.is_err());
+        assert!(!events(&app.events).contains(&"request done"));
+    }
+}
@@ -1,36 +1,362 @@
-use axum::{http::StatusCode, response::IntoResponse};
+//! Request errors, rendered as RFC 7807 `application/problem+json`
+//!
+//! Handlers return [`RequestResult`]. Errors from `error_stack` can be
+//! propagated with `?`: the full report is logged, and the client only gets
+//! the [`RequestError`] found in it (or a generic internal error).
+use std::time::Duration;
+
+use axum::{
+    body::{self, Body, Full},
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use error_stack::{Context, Report};
+use serde::Serialize;
 use thiserror::Error;
+use tracing::{debug, error};
+
+use super::REQUEST_ID_HEADER;
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum RequestError {
-    #[error("webhook verification failed")]
+    #[error("webhook verification failed: {0}")]
     WebhookVerification(#[from] WebhookVerificationError),
+    #[error("not found")]
+    NotFound,
+    #[error("validation failed")]
+    Validation { errors: Vec<FieldError> },
+    #[error("{detail}")]
+    Conflict { detail: String },
+    #[error("unauthorized")]
+    Unauthorized,
     #[error("internal error")]
     InternalError,
+    #[error("request timed out")]
+    Timeout,
+    #[error("request body too large")]
+    PayloadTooLarge,
+    #[error("service overloaded")]
+    Overloaded,
+    #[error("rate limit exceeded")]
+    RateLimited { retry_after: Duration },
+    /// Any status not covered by the other variants
+    #[error("{detail}")]
+    Other { status: StatusCode, detail: String },
 }
 
 pub type RequestResult<T> = std::result::Result<T, RequestError>;
 
-#[derive(Error, Debug)]
+/// Why a single request field was rejected
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl RequestError {
+    /// Validation error for a single field
+    pub fn invalid_field(field: impl Into<String>, message: impl Into<String>) -> Self {
+        RequestError::Validation {
+            errors: vec![FieldError::new(field, message)],
+        }
+    }
+
+    pub fn conflict(detail: impl Into<String>) -> Self {
+        RequestError::Conflict {
+            detail: detail.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RequestError::WebhookVerification(e) => match e {
+                WebhookVerificationError::MissingTag => StatusCode::BAD_REQUEST,
+                WebhookVerificationError::InvalidTag { tag: _ } => StatusCode::UNAUTHORIZED,
+                WebhookVerificationError::Expired => StatusCode::UNAUTHORIZED,
+            },
+            RequestError::NotFound => StatusCode::NOT_FOUND,
+            RequestError::Validation { errors: _ } => StatusCode::UNPROCESSABLE_ENTITY,
+            RequestError::Conflict { detail: _ } => StatusCode::CONFLICT,
+            RequestError::Unauthorized => StatusCode::UNAUTHORIZED,
+            RequestError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            RequestError::Timeout => StatusCode::REQUEST_TIMEOUT,
+            RequestError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            RequestError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            RequestError::RateLimited { retry_after: _ } => StatusCode::TOO_MANY_REQUESTS,
+            RequestError::Other { status, detail: _ } => *status,
+        }
+    }
+
+    fn problem(&self) -> Problem {
+        let status = self.status();
+        Problem {
+            type_: "about:blank",
+            title: status.canonical_reason().unwrap_or("Unknown Error"),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            request_id: None,
+            errors: match self {
+                RequestError::Validation { errors } => errors.clone(),
+                _ => vec![],
+            },
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone)]
 pub enum WebhookVerificationError {
     #[error("missing verification tag")]
     MissingTag,
     #[error("invalid verification tag")]
     InvalidTag { tag: Vec<u8> },
+    #[error("verification tag expired")]
+    Expired,
 }
 
 pub type WebhookVerificationResult<T> = std::result::Result<T, WebhookVerificationError>;
 
+/// Log the whole report, respond with the `RequestError` it carries (if any)
+///
+/// Allows using `?` on `error_stack::Result` in handlers. Attach a
+/// `RequestError` (e.g. with `change_context(RequestError::NotFound)`) to
+/// pick the response; anything else is a 500.
+impl<C: Context> From<Report<C>> for RequestError {
+    fn from(report: Report<C>) -> Self {
+        let error = report
+            .downcast_ref::<RequestError>()
+            .cloned()
+            .unwrap_or(RequestError::InternalError);
+        if error.status().is_server_error() {
+            error!("Request failed: {report:?}");
+        } else {
+            debug!("Request failed: {report:?}");
+        }
+        error
+    }
+}
+
+/// RFC 7807 problem details
+#[derive(Debug, Clone, Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+impl Problem {
+    fn to_body(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("problem serializes")
+    }
+}
+
 impl IntoResponse for RequestError {
-    fn into_response(self) -> axum::response::Response {
-        let code = match self {
-            RequestError::WebhookVerification(e) => match e {
-                WebhookVerificationError::MissingTag => StatusCode::BAD_REQUEST,
-                WebhookVerificationError::InvalidTag { tag: _ } => StatusCode::UNAUTHORIZED,
-            },
-            RequestError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+    fn into_response(self) -> Response {
+        let problem = self.problem();
+
+        let mut response = (
+            self.status(),
+            [(header::CONTENT_TYPE, PROBLEM_CONTENT_TYPE)],
+            problem.to_body(),
+        )
+            .into_response();
+        if let RequestError::RateLimited { retry_after } = self {
+            let retry_after = retry_after.as_secs_f64().ceil().to_string();
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from_str(&retry_after).expect("number is a valid header"),
+            );
+        }
+        // the request ID is only known to the middleware, see `add_problem_request_id`
+        response.extensions_mut().insert(problem);
+        response
+    }
+}
+
+/// Add the request ID to problem responses; meant for `from_fn` middleware
+pub(crate) async fn add_problem_request_id(req: Request<Body>, next: Next<Body>) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(ToOwned::to_owned);
+
+    let mut response = next.run(req).await;
+    if let (Some(request_id), Some(problem)) =
+        (request_id, response.extensions_mut().remove::<Problem>())
+    {
+        let problem = Problem {
+            request_id: Some(request_id),
+            ..problem
+        };
+        response.headers_mut().remove(header::CONTENT_LENGTH);
+        *response.body_mut() = body::boxed(Full::from(problem.to_body()));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::HeaderMap, routing::get, Router};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::axum::add_request_layers;
+
+    async fn parts(response: Response) -> (StatusCode, HeaderMap, serde_json::Value) {
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            serde_json::from_slice(&body).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn problem_response() {
+        let (status, headers, problem) = parts(RequestError::NotFound.into_response()).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers[header::CONTENT_TYPE], PROBLEM_CONTENT_TYPE);
+        assert_eq!(
+            problem,
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "not found",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn problem_with_field_errors() {
+        let (status, _, problem) =
+            parts(RequestError::invalid_field("name", "too long").into_response()).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["title"], "Unprocessable Entity");
+        assert_eq!(
+            problem["errors"],
+            json!([{"field": "name", "message": "too long"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn problem_with_other_status() {
+        let error = RequestError::Other {
+            status: StatusCode::IM_A_TEAPOT,
+            detail: "short and stout".into(),
+        };
+        let (status, _, problem) = parts(error.into_response()).await;
+
+        assert_eq!(status, StatusCode::IM_A_TEAPOT);
+        assert_eq!(problem["status"], 418);
+        assert_eq!(problem["title"], "I'm a teapot");
+        assert_eq!(problem["detail"], "short and stout");
+    }
+
+    #[tokio::test]
+    async fn rate_limited_has_retry_after() {
+        let error = RequestError::RateLimited {
+            retry_after: Duration::from_millis(1500),
         };
+        let (status, headers, problem) = parts(error.into_response()).await;
+
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // rounded up to whole seconds
+        assert_eq!(headers[header::RETRY_AFTER], "2");
+        assert_eq!(problem["status"], 429);
+
+        let (_, headers, _) = parts(RequestError::Overloaded.into_response()).await;
+        assert!(!headers.contains_key(header::RETRY_AFTER));
+    }
+
+    #[derive(Error, Debug)]
+    #[error("database error")]
+    struct DbError;
+
+    #[test]
+    fn request_error_from_report() {
+        let report = Report::new(DbError).change_context(RequestError::NotFound);
+        assert_eq!(RequestError::from(report).status(), StatusCode::NOT_FOUND);
+
+        let report = Report::new(DbError);
+        assert_eq!(
+            RequestError::from(report).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    fn router() -> Router {
+        let router = Router::new().route("/ok", get(|| async { "ok" })).route(
+            "/missing",
+            get(|| async { RequestResult::<()>::Err(RequestError::NotFound) }),
+        );
+        add_request_layers(router)
+    }
+
+    async fn get_with_request_id(path: &str, request_id: Option<&str>) -> Response {
+        let mut req = Request::get(path);
+        if let Some(request_id) = request_id {
+            req = req.header(REQUEST_ID_HEADER, request_id);
+        }
+        router()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn problem_gets_request_id() {
+        let response = get_with_request_id("/missing", Some("req-1")).await;
+        let content_length = response.headers().get(header::CONTENT_LENGTH).cloned();
+        let (status, headers, problem) = parts(response).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers[header::CONTENT_TYPE], PROBLEM_CONTENT_TYPE);
+        assert_eq!(headers[REQUEST_ID_HEADER], "req-1");
+        assert_eq!(problem["request_id"], "req-1");
+        assert_eq!(problem["status"], 404);
+        // the old length would not match the new body
+        assert!(content_length.is_none());
+    }
+
+    #[tokio::test]
+    async fn problem_gets_generated_request_id() {
+        let response = get_with_request_id("/missing", None).await;
+        let (_, headers, problem) = parts(response).await;
+
+        assert_eq!(
+            problem["request_id"],
+            headers[REQUEST_ID_HEADER].to_str().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn other_responses_are_untouched() {
+        let response = get_with_request_id("/ok", Some("req-1")).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
 
-        (code, code.canonical_reason().unwrap_or("Unknown Error")).into_response()
+        assert_eq!(body, "ok");
     }
 }
@@ -0,0 +1,302 @@
+//! Per-request limits: rate limit, body size and timeout
+//!
+//! Defaults come from [`crate::opts::LimitOpts`]. Timeout and body size can be
+//! overridden per route (e.g. a longer timeout for webhooks) with
+//! [`crate::AppBuilder::set_route_limits`].
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::MatchedPath,
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use futures_util::StreamExt;
+
+use super::RequestError;
+use crate::opts::LimitOpts;
+
+/// Limits overriding the defaults for a single route
+#[derive(Debug, Clone, Default)]
+pub struct RouteLimits {
+    pub timeout: Option<Duration>,
+    pub max_body_size: Option<usize>,
+}
+
+impl RouteLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = Some(max_body_size);
+        self
+    }
+}
+
+/// Token bucket shared by all the routes
+#[derive(Debug)]
+struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            per_second: f64::from(per_second),
+            burst: f64::from(burst),
+            state: Mutex::new((f64::from(burst), Instant::now())),
+        }
+    }
+
+    /// Take a token, or return how long until one is available
+    fn acquire(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().expect("lock not poisoned");
+        let (tokens, last) = &mut *state;
+
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * self.per_second).min(self.burst);
+        *last = now;
+
+        if 1.0 <= *tokens {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - *tokens) / self.per_second))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Limits {
+    timeout: Duration,
+    max_body_size: usize,
+    rate_limiter: Option<RateLimiter>,
+    routes: BTreeMap<String, RouteLimits>,
+}
+
+impl Limits {
+    pub(crate) fn new(opts: &LimitOpts, routes: BTreeMap<String, RouteLimits>) -> Self {
+        Self {
+            timeout: Duration::from_secs(opts.request_timeout_secs),
+            max_body_size: opts.max_body_size,
+            rate_limiter: opts.rate_limit.map(|per_second| {
+                RateLimiter::new(per_second, opts.rate_limit_burst.unwrap_or(per_second))
+            }),
+            routes,
+        }
+    }
+
+    /// Enforce the limits on a request; meant for `from_fn` middleware
+    pub(crate) async fn enforce(self: Arc<Self>, req: Request<Body>, next: Next<Body>) -> Response {
+        match self.check(req, next).await {
+            Ok(response) => response,
+            Err(e) => e.into_response(),
+        }
+    }
+
+    async fn check(&self, req: Request<Body>, next: Next<Body>) -> Result<Response, RequestError> {
+        if let Some(rate_limiter) = self.rate_limiter.as_ref() {
+            rate_limiter
+                .acquire()
+                .map_err(|retry_after| RequestError::RateLimited { retry_after })?;
+        }
+
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .and_then(|path| self.routes.get(path.as_str()));
+        let timeout = route.and_then(|r| r.timeout).unwrap_or(self.timeout);
+        let max_body_size = route
+            .and_then(|r| r.max_body_size)
+            .unwrap_or(self.max_body_size);
+
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<usize>().ok());
+        let too_large = Arc::new(AtomicBool::new(false));
+        let req = match content_length {
+            Some(len) if max_body_size < len => return Err(RequestError::PayloadTooLarge),
+            Some(_) => req,
+            // no length upfront (e.g. chunked encoding); check as it streams in
+            None => req.map(|body| limit_body(body, max_body_size, too_large.clone())),
+        };
+
+        let response = tokio::time::timeout(timeout, next.run(req))
+            .await
+            .map_err(|_| RequestError::Timeout)?;
+
+        // extractors report the failed body as a generic `400 Bad Request`
+        if too_large.load(Ordering::Relaxed) {
+            return Err(RequestError::PayloadTooLarge);
+        }
+        Ok(response)
+    }
+}
+
+/// Fail the body stream once it's over `max_body_size`, setting `too_large`
+fn limit_body(body: Body, max_body_size: usize, too_large: Arc<AtomicBool>) -> Body {
+    let mut size = 0;
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk = chunk?;
+        size += chunk.len();
+        if max_body_size < size {
+            too_large.store(true, Ordering::Relaxed);
+            return Err(BoxError::from(RequestError::PayloadTooLarge));
+        }
+        Ok(chunk)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Bytes, http::StatusCode, routing::post, Router};
+    use futures_util::stream;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::axum::configure_axum_router;
+
+    #[test]
+    fn rate_limiter_allows_bursts() {
+        let limiter = RateLimiter::new(1, 2);
+
+        assert!(limiter.acquire().is_ok());
+        assert!(limiter.acquire().is_ok());
+        let retry_after = limiter.acquire().unwrap_err();
+        assert!(Duration::ZERO < retry_after && retry_after <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn rate_limiter_refills() {
+        let limiter = RateLimiter::new(1000, 1);
+
+        assert!(limiter.acquire().is_ok());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(limiter.acquire().is_ok());
+    }
+
+    fn opts(max_body_size: usize) -> LimitOpts {
+        LimitOpts {
+            request_timeout_secs: 30,
+            max_body_size,
+            concurrency_limit: None,
+            load_shed: false,
+            rate_limit: None,
+            rate_limit_burst: None,
+        }
+    }
+
+    /// Routes echoing the body size; `/small` and `/slow` have their own limits
+    fn router(opts: &LimitOpts) -> Router {
+        let routes = BTreeMap::from([
+            ("/small".to_owned(), RouteLimits::new().max_body_size(4)),
+            (
+                "/slow".to_owned(),
+                RouteLimits::new().timeout(Duration::from_secs(1)),
+            ),
+        ]);
+        let echo_len = |body: Bytes| async move { body.len().to_string() };
+        let router = Router::new()
+            .route("/", post(echo_len))
+            .route("/small", post(echo_len))
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }),
+            );
+        configure_axum_router(router, Limits::new(opts, routes), opts)
+    }
+
+    async fn status(router: Router, path: &str, body: &'static str, streamed: bool) -> StatusCode {
+        let req = Request::post(path);
+        let req = if streamed {
+            req.body(Body::wrap_stream(stream::iter(
+                body.as_bytes()
+                    .chunks(2)
+                    .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec())),
+            )))
+        } else {
+            req.header(header::CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+        };
+        router.oneshot(req.unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn body_size() {
+        let router = router(&opts(8));
+
+        for streamed in [false, true] {
+            assert_eq!(
+                status(router.clone(), "/", "12345678", streamed).await,
+                StatusCode::OK
+            );
+            assert_eq!(
+                status(router.clone(), "/", "123456789", streamed).await,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "streamed: {streamed}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn route_overrides() {
+        let router = router(&opts(8));
+
+        for streamed in [false, true] {
+            assert_eq!(
+                status(router.clone(), "/small", "1234", streamed).await,
+                StatusCode::OK
+            );
+            assert_eq!(
+                status(router.clone(), "/small", "12345", streamed).await,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "streamed: {streamed}"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn route_timeout() {
+        assert_eq!(
+            status(router(&opts(8)), "/slow", "", false).await,
+            StatusCode::REQUEST_TIMEOUT
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let opts = LimitOpts {
+            rate_limit: Some(1),
+            ..opts(8)
+        };
+        let router = router(&opts);
+
+        assert_eq!(status(router.clone(), "/", "", false).await, StatusCode::OK);
+        assert_eq!(
+            status(router, "/", "", false).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Serving a router over TCP (plain or TLS) or a Unix socket
+//!
+//! HTTP/1 and HTTP/2 are both supported on every listener: with TLS the
+//! protocol is negotiated with ALPN, otherwise HTTP/2 is detected by its
+//! connection preface ("prior knowledge" h2c).
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use error_stack::{IntoReport, Report, ResultExt};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::oneshot,
+};
+use tracing::{info, warn};
+
+use crate::{opts::CommonOpts, AppError, AppResult};
+
+/// How often TLS certificate files are checked for changes
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(SocketAddr),
+    /// Already bound, see [`Listener::bind_tcp`]
+    TcpBound(std::net::TcpListener),
+    Tls {
+        addr: SocketAddr,
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    Unix(PathBuf),
+}
+
+impl Listener {
+    pub(crate) fn from_opts(opts: &CommonOpts) -> Self {
+        let addr = SocketAddr::new(opts.bind_addr, opts.listen_port);
+        match (&opts.unix_socket, &opts.tls_cert, &opts.tls_key) {
+            (Some(path), _, _) => Listener::Unix(path.clone()),
+            (None, Some(cert), Some(key)) => Listener::Tls {
+                addr,
+                cert: cert.clone(),
+                key: key.clone(),
+            },
+            _ => Listener::Tcp(addr),
+        }
+    }
+
+    /// Bind right away, to fail early if `addr` is not available
+    pub(crate) fn bind_tcp(addr: SocketAddr) -> AppResult<Self> {
+        let listener = std::net::TcpListener::bind(addr)
+            .report()
+            .change_context(AppError)
+            .attach_printable_lazy(|| format!("Failed to bind: {addr}"))?;
+        Ok(Listener::TcpBound(listener))
+    }
+}
+
+/// Serve `router` until `shutdown` completes, then finish in-flight requests
+///
+/// Fails if they are not done within `grace_period`.
+pub(crate) async fn serve(
+    router: Router,
+    listener: Listener,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+    grace_period: Duration,
+) -> AppResult<()> {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let server = serve_until(router, listener, async move {
+        shutdown.await;
+        let _ = shutdown_tx.send(());
+    });
+    tokio::pin!(server);
+
+    tokio::select! {
+        res = &mut server => res,
+        _ = async {
+            if shutdown_rx.await.is_err() {
+                // server stopped on its own, and the other branch completes
+                std::future::pending::<()>().await;
+            }
+            tokio::time::sleep(grace_period).await;
+        } => {
+            warn!("requests still in flight after {}s, closing", grace_period.as_secs());
+            Err(Report::new(AppError)
+                .attach_printable("Requests still in flight after the shutdown grace period"))
+        }
+    }
+}
+
+async fn serve_until(
+    router: Router,
+    listener: Listener,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> AppResult<()> {
+    let make_service = router.into_make_service();
+
+    match listener {
+        Listener::Tcp(addr) => {
+            info!("listening on http://{addr}");
+            axum_server::bind(addr)
+                .handle(graceful_shutdown_handle(shutdown))
+                .serve(make_service)
+                .await
+                .report()
+                .change_context(AppError)
+        }
+        Listener::TcpBound(listener) => {
+            let addr = listener.local_addr().report().change_context(AppError)?;
+            info!("listening on http://{addr}");
+            axum_server::from_tcp(listener)
+                .handle(graceful_shutdown_handle(shutdown))
+                .serve(make_service)
+                .await
+                .report()
+                .change_context(AppError)
+        }
+        Listener::Tls { addr, cert, key } => {
+            let config = RustlsConfig::from_pem_file(&cert, &key)
+                .await
+                .report()
+                .change_context(AppError)
+                .attach_printable_lazy(|| {
+                    format!(
+                        "Failed to load TLS cert: {} key: {}",
+                        cert.display(),
+                        key.display()
+                    )
+                })?;
+            tokio::spawn(reload_tls_on_change(config.clone(), cert, key));
+
+            info!("listening on https://{addr}");
+            axum_server::bind_rustls(addr, config)
+                .handle(graceful_shutdown_handle(shutdown))
+                .serve(make_service)
+                .await
+                .report()
+                .change_context(AppError)
+        }
+        Listener::Unix(path) => {
+            // left behind if the previous instance did not shut down cleanly
+            if path.exists() {
+                std::fs::remove_file(&path)
+                    .report()
+                    .change_context(AppError)
+                    .attach_printable_lazy(|| {
+                        format!("Failed to remove stale socket: {}", path.display())
+                    })?;
+            }
+            let listener = UnixListener::bind(&path)
+                .report()
+                .change_context(AppError)
+                .attach_printable_lazy(|| format!("Failed to bind: {}", path.display()))?;
+
+            info!("listening on unix:{}", path.display());
+            let res = hyper::Server::builder(UnixAccept(listener))
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown)
+                .await
+                .report()
+                .change_context(AppError);
+            let _ = std::fs::remove_file(&path);
+            res
+        }
+    }
+}
+
+fn graceful_shutdown_handle(shutdown: impl Future<Output = ()> + Send + 'static) -> Handle {
+    let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.await;
+            handle.graceful_shutdown(None);
+        }
+    });
+    handle
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reload the certificate when the files change (e.g. renewed by cert-manager)
+async fn reload_tls_on_change(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let mut last_modified = (modified(&cert), modified(&key));
+    loop {
+        tokio::time::sleep(TLS_RELOAD_INTERVAL).await;
+
+        let current = (modified(&cert), modified(&key));
+        if current == last_modified {
+            continue;
+        }
+        // on failure (e.g. only one of the files updated so far) just retry next time
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => {
+                info!(cert = %cert.display(), "TLS certificate reloaded");
+                last_modified = current;
+            }
+            Err(e) => warn!(cert = %cert.display(), "Failed to reload TLS certificate: {e}"),
+        }
+    }
+}
+
+struct UnixAccept(UnixListener);
+
+impl hyper::server::accept::Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|res| Some(res.map(|(stream, _addr)| stream)))
+    }
+}
@@ -0,0 +1,433 @@
+//! Webhook signature verification
+//!
+//! Add the provider (with its secret) as an `Extension` and take
+//! [`VerifiedWebhook`] as the last handler argument:
+//!
+//! ```ignore
+//! router
+//!     .route("/github/webhook", post(handler))
+//!     .layer(Extension(GitHub::new(&secret)));
+//!
+//! async fn handler(webhook: VerifiedWebhook<GitHub>) -> RequestResult<()> { .. }
+//! ```
+//!
+//! Signatures and tokens are always compared in constant time.
+use std::{
+    marker::PhantomData,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    extract::{FromRequest, RequestParts},
+    http::{header::HeaderName, HeaderMap},
+    response::IntoResponse,
+};
+use ring::{constant_time, hmac};
+use serde::de::DeserializeOwned;
+use tracing::error;
+
+use super::{RequestError, RequestResult, WebhookVerificationError, WebhookVerificationResult};
+
+/// A way of proving a webhook request comes from whoever holds the secret
+pub trait WebhookProvider: Clone + Send + Sync + 'static {
+    fn verify(&self, headers: &HeaderMap, body: &[u8]) -> WebhookVerificationResult<()>;
+}
+
+/// Extracts the body of a webhook request, rejecting it unless verified by `P`
+///
+/// `P` must be available as an `Extension`.
+#[derive(Debug, Clone)]
+pub struct VerifiedWebhook<P> {
+    pub body: Bytes,
+    _provider: PhantomData<P>,
+}
+
+impl<P> VerifiedWebhook<P> {
+    /// Parse the body as json
+    pub fn json<T: DeserializeOwned>(&self) -> RequestResult<T> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| RequestError::invalid_field("body", e.to_string()))
+    }
+}
+
+#[async_trait]
+impl<P: WebhookProvider> FromRequest<Body> for VerifiedWebhook<P> {
+    type Rejection = RequestError;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+        let provider = match req.extensions().get::<P>() {
+            Some(provider) => provider.clone(),
+            None => {
+                error!(
+                    "Webhook provider not configured: {}",
+                    std::any::type_name::<P>()
+                );
+                return Err(RequestError::InternalError);
+            }
+        };
+        let body = Bytes::from_request(req).await.map_err(|e| {
+            let detail = e.to_string();
+            RequestError::Other {
+                status: e.into_response().status(),
+                detail,
+            }
+        })?;
+
+        provider.verify(req.headers(), &body)?;
+
+        Ok(Self {
+            body,
+            _provider: PhantomData,
+        })
+    }
+}
+
+fn header<'h>(headers: &'h HeaderMap, name: &HeaderName) -> WebhookVerificationResult<&'h [u8]> {
+    headers
+        .get(name)
+        .map(|value| value.as_bytes())
+        .ok_or(WebhookVerificationError::MissingTag)
+}
+
+fn invalid_tag(tag: &[u8]) -> WebhookVerificationError {
+    WebhookVerificationError::InvalidTag {
+        tag: tag.to_owned(),
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of the body, in a header
+#[derive(Debug, Clone)]
+pub struct Hmac {
+    header: HeaderName,
+    prefix: String,
+    key: hmac::Key,
+}
+
+impl Hmac {
+    pub fn sha256(header: HeaderName, secret: &str) -> Self {
+        Self {
+            header,
+            prefix: String::new(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    /// Expect the signature to be prefixed, e.g. with `sha256=`
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+impl WebhookProvider for Hmac {
+    fn verify(&self, headers: &HeaderMap, body: &[u8]) -> WebhookVerificationResult<()> {
+        let tag = header(headers, &self.header)?;
+        let signature = tag
+            .strip_prefix(self.prefix.as_bytes())
+            .and_then(|hex_signature| hex::decode(hex_signature).ok())
+            .ok_or_else(|| invalid_tag(tag))?;
+        hmac::verify(&self.key, body, &signature).map_err(|_| invalid_tag(tag))
+    }
+}
+
+/// GitHub `X-Hub-Signature-256`
+#[derive(Debug, Clone)]
+pub struct GitHub(Hmac);
+
+impl GitHub {
+    pub fn new(secret: &str) -> Self {
+        Self(Hmac::sha256(HeaderName::from_static("x-hub-signature-256"), secret).prefix("sha256="))
+    }
+}
+
+impl WebhookProvider for GitHub {
+    fn verify(&self, headers: &HeaderMap, body: &[u8]) -> WebhookVerificationResult<()> {
+        self.0.verify(headers, body)
+    }
+}
+
+/// GitLab `X-Gitlab-Token`: the secret itself, not a signature
+#[derive(Debug, Clone)]
+pub struct GitLab {
+    token: String,
+}
+
+impl GitLab {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            token: token.into(),
+        }
+    }
+}
+
+impl WebhookProvider for GitLab {
+    fn verify(&self, headers: &HeaderMap, _body: &[u8]) -> WebhookVerificationResult<()> {
+        let tag = header(headers, &HeaderName::from_static("x-gitlab-token"))?;
+        constant_time::verify_slices_are_equal(tag, self.token.as_bytes())
+            .map_err(|_| invalid_tag(tag))
+    }
+}
+
+/// Stripe `Stripe-Signature`: `t=<unix time>,v1=<hex HMAC-SHA256 of "<t>.<body>">`
+///
+/// Requests signed longer than `tolerance` ago (5 minutes by default) are
+/// rejected, so captured requests can't be replayed later.
+#[derive(Debug, Clone)]
+pub struct Stripe {
+    key: hmac::Key,
+    tolerance: Duration,
+}
+
+impl Stripe {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            tolerance: Duration::from_secs(300),
+        }
+    }
+
+    pub fn tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+}
+
+impl WebhookProvider for Stripe {
+    fn verify(&self, headers: &HeaderMap, body: &[u8]) -> WebhookVerificationResult<()> {
+        let tag = header(headers, &HeaderName::from_static("stripe-signature"))?;
+
+        let mut timestamp = None;
+        let mut signatures = vec![];
+        for item in tag.split(|&b| b == b',') {
+            let mut key_value = item.splitn(2, |&b| b == b'=');
+            match (key_value.next(), key_value.next()) {
+                (Some(b"t"), Some(value)) => timestamp = Some(value),
+                // there can be several, e.g. while the secret is being rolled
+                (Some(b"v1"), Some(value)) => signatures.extend(hex::decode(value).ok()),
+                _ => {}
+            }
+        }
+        let timestamp = timestamp.ok_or_else(|| invalid_tag(tag))?;
+
+        let signed_at = std::str::from_utf8(timestamp)
+            .ok()
+            .and_then(|t| t.parse::<u64>().ok())
+            .map(|t| UNIX_EPOCH + Duration::from_secs(t))
+            .ok_or_else(|| invalid_tag(tag))?;
+        let age = SystemTime::now()
+            .duration_since(signed_at)
+            .unwrap_or_else(|e| e.duration());
+        if self.tolerance < age {
+            return Err(WebhookVerificationError::Expired);
+        }
+
+        let mut ctx = hmac::Context::with_key(&self.key);
+        ctx.update(timestamp);
+        ctx.update(b".");
+        ctx.update(body);
+        let expected = ctx.sign();
+
+        if signatures.iter().any(|signature| {
+            constant_time::verify_slices_are_equal(expected.as_ref(), signature).is_ok()
+        }) {
+            Ok(())
+        } else {
+            Err(invalid_tag(tag))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    // Example from GitHub's "Validating webhook deliveries" docs
+    const GITHUB_SECRET: &str = "It's a Secret to Everybody";
+    const GITHUB_BODY: &[u8] = b"Hello, World!";
+    const GITHUB_SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn is_missing(res: WebhookVerificationResult<()>) -> bool {
+        matches!(res, Err(WebhookVerificationError::MissingTag))
+    }
+
+    fn is_invalid(res: WebhookVerificationResult<()>) -> bool {
+        matches!(res, Err(WebhookVerificationError::InvalidTag { .. }))
+    }
+
+    #[test]
+    fn hmac() {
+        let provider = Hmac::sha256(HeaderName::from_static("x-signature"), GITHUB_SECRET);
+        let signature = GITHUB_SIGNATURE.trim_start_matches("sha256=");
+
+        assert!(provider
+            .verify(&headers("x-signature", signature), GITHUB_BODY)
+            .is_ok());
+        assert!(is_invalid(
+            provider.verify(&headers("x-signature", signature), b"Hello, World?")
+        ));
+        assert!(is_invalid(
+            provider.verify(&headers("x-signature", GITHUB_SIGNATURE), GITHUB_BODY)
+        ));
+        assert!(is_missing(provider.verify(&HeaderMap::new(), GITHUB_BODY)));
+    }
+
+    #[test]
+    fn github() {
+        let provider = GitHub::new(GITHUB_SECRET);
+
+        assert!(provider
+            .verify(
+                &headers("x-hub-signature-256", GITHUB_SIGNATURE),
+                GITHUB_BODY
+            )
+            .is_ok());
+        assert!(is_invalid(provider.verify(
+            &headers("x-hub-signature-256", GITHUB_SIGNATURE),
+            b"Hello, World?"
+        )));
+        assert!(is_invalid(GitHub::new("wrong secret").verify(
+            &headers("x-hub-signature-256", GITHUB_SIGNATURE),
+            GITHUB_BODY
+        )));
+        assert!(is_missing(provider.verify(&HeaderMap::new(), GITHUB_BODY)));
+    }
+
+    #[test]
+    fn github_short_or_malformed_signature() {
+        let provider = GitHub::new(GITHUB_SECRET);
+        let truncated = &GITHUB_SIGNATURE[..GITHUB_SIGNATURE.len() - 2];
+
+        for tag in [
+            "",
+            "sha",
+            "sha256",
+            "sha256=",
+            "sha256=0",
+            "sha256=zz",
+            truncated,
+            GITHUB_SIGNATURE.trim_start_matches("sha256="),
+            &GITHUB_SIGNATURE.replace("sha256=", "sha1="),
+        ] {
+            assert!(
+                is_invalid(provider.verify(&headers("x-hub-signature-256", tag), GITHUB_BODY)),
+                "{tag:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn gitlab() {
+        let provider = GitLab::new("token");
+
+        assert!(provider
+            .verify(&headers("x-gitlab-token", "token"), b"{}")
+            .is_ok());
+        assert!(is_invalid(
+            provider.verify(&headers("x-gitlab-token", "tokem"), b"{}")
+        ));
+        assert!(is_invalid(
+            provider.verify(&headers("x-gitlab-token", "token2"), b"{}")
+        ));
+        assert!(is_invalid(
+            provider.verify(&headers("x-gitlab-token", ""), b"{}")
+        ));
+        assert!(is_missing(provider.verify(&HeaderMap::new(), b"{}")));
+    }
+
+    const STRIPE_SECRET: &str = "whsec_test";
+    const STRIPE_BODY: &[u8] = br#"{"id":"evt_1"}"#;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn stripe_tag(secret: &str, timestamp: u64, body: &[u8]) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let signed = [format!("{timestamp}.").as_bytes(), body].concat();
+        format!(
+            "t={timestamp},v1={}",
+            hex::encode(hmac::sign(&key, &signed))
+        )
+    }
+
+    #[test]
+    fn stripe() {
+        let provider = Stripe::new(STRIPE_SECRET);
+        let t = now();
+        let tag = stripe_tag(STRIPE_SECRET, t, STRIPE_BODY);
+
+        assert!(provider
+            .verify(&headers("stripe-signature", &tag), STRIPE_BODY)
+            .is_ok());
+        // secret being rolled: one of the signatures matches
+        let rolled = format!(
+            "{tag},v1={}",
+            stripe_tag("old secret", t, STRIPE_BODY)
+                .split_once("v1=")
+                .unwrap()
+                .1
+        );
+        assert!(provider
+            .verify(&headers("stripe-signature", &rolled), STRIPE_BODY)
+            .is_ok());
+
+        assert!(is_invalid(
+            provider.verify(&headers("stripe-signature", &tag), b"{}")
+        ));
+        assert!(is_invalid(provider.verify(
+            &headers(
+                "stripe-signature",
+                &stripe_tag("wrong secret", t, STRIPE_BODY)
+            ),
+            STRIPE_BODY
+        )));
+        // timestamp is part of the signed payload
+        let tampered = tag.replacen(&format!("t={}", t), &format!("t={}", t - 1), 1);
+        assert!(is_invalid(
+            provider.verify(&headers("stripe-signature", &tampered), STRIPE_BODY)
+        ));
+        for malformed in ["", "t=", "v1=00", "t=abc,v1=00", &format!("t={}", t)] {
+            assert!(
+                is_invalid(provider.verify(&headers("stripe-signature", malformed), STRIPE_BODY)),
+                "{malformed:?}"
+            );
+        }
+        assert!(is_missing(provider.verify(&HeaderMap::new(), STRIPE_BODY)));
+    }
+
+    #[test]
+    fn stripe_tolerance() {
+        let provider = Stripe::new(STRIPE_SECRET).tolerance(Duration::from_secs(60));
+
+        for (timestamp, expired) in [
+            (now() - 30, false),
+            (now() - 600, true),
+            // clocks can be off either way
+            (now() + 30, false),
+            (now() + 600, true),
+        ] {
+            let tag = stripe_tag(STRIPE_SECRET, timestamp, STRIPE_BODY);
+            let res = provider.verify(&headers("stripe-signature", &tag), STRIPE_BODY);
+            assert_eq!(
+                matches!(res, Err(WebhookVerificationError::Expired)),
+                expired,
+                "{timestamp}: {res:?}"
+            );
+            assert_eq!(res.is_ok(), !expired);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Config file (`--config`), TOML or YAML, e.g. mounted from a `ConfigMap`
+//!
+//! Top level keys are the long names of the command line options:
+//!
+//! ```toml
+//! listen = 8000
+//! request-timeout = 10
+//! load-shed = true
+//!
+//! [reloadable]
+//! greeting = "hi"
+//! ```
+//!
+//! Options set in the file are overridden by the environment, which in turn
+//! is overridden by the command line. The `reloadable` table is not an
+//! option: it is re-read whenever the file changes and published through
+//! [`crate::AppBuilder::reloadable_config`].
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use error_stack::{IntoReport, ResultExt};
+use serde::de::DeserializeOwned;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{opts::CommonOpts, AppBuilder, AppError, AppResult};
+
+/// `--config`/`CONFIG_FILE`, read before all the other options
+const CONFIG_FILE_ARG: &str = "config";
+const CONFIG_FILE_ENV: &str = "CONFIG_FILE";
+
+/// Table of settings that can change without a restart
+const RELOADABLE_KEY: &str = "reloadable";
+
+/// How often the config file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Find `--config` in the raw arguments, falling back to `CONFIG_FILE`
+pub(crate) fn file_path(args: &[OsString]) -> Option<PathBuf> {
+    let long = format!("--{CONFIG_FILE_ARG}");
+    let long_eq = format!("--{CONFIG_FILE_ARG}=");
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg == long {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix(&long_eq) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(CONFIG_FILE_ENV).map(PathBuf::from)
+}
+
+/// Parse the file as TOML, or YAML if it has a `.yaml`/`.yml` extension
+pub(crate) fn load(path: &Path) -> AppResult<serde_json::Map<String, serde_json::Value>> {
+    let content = std::fs::read_to_string(path)
+        .report()
+        .change_context(AppError)
+        .attach_printable_lazy(|| format!("Failed to read config file: {}", path.display()))?;
+
+    let is_yaml = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml" | "yml")
+    );
+    let value: serde_json::Value = if is_yaml {
+        serde_yaml::from_str(&content)
+            .report()
+            .change_context(AppError)
+    } else {
+        toml::from_str(&content).report().change_context(AppError)
+    }
+    .attach_printable_lazy(|| format!("Failed to parse config file: {}", path.display()))?;
+
+    match value {
+        serde_json::Value::Object(map) => Ok(map),
+        // e.g. an empty YAML file
+        serde_json::Value::Null => Ok(serde_json::Map::new()),
+        _ => Err(error_stack::Report::new(AppError)
+            .attach_printable(format!("Config file is not a table: {}", path.display()))),
+    }
+}
+
+/// Option values from the file, as they would be passed on the command line
+pub(crate) fn option_values(
+    path: &Path,
+    file: &serde_json::Map<String, serde_json::Value>,
+) -> AppResult<Vec<(String, String)>> {
+    file.iter()
+        .filter(|(key, _)| key.as_str() != RELOADABLE_KEY)
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+                _ => {
+                    return Err(error_stack::Report::new(AppError).attach_printable(format!(
+                        "Config file option `{key}` must be a string, number or boolean: {}",
+                        path.display()
+                    )))
+                }
+            };
+            Ok((key.clone(), value))
+        })
+        .collect()
+}
+
+fn reloadable<T: DeserializeOwned>(
+    file: &serde_json::Map<String, serde_json::Value>,
+) -> AppResult<T> {
+    let value = file
+        .get(RELOADABLE_KEY)
+        .cloned()
+        .unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::new()));
+    serde_json::from_value(value)
+        .report()
+        .change_context(AppError)
+        .attach_printable_lazy(|| format!("Invalid `{RELOADABLE_KEY}` config"))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl AppBuilder<CommonOpts> {
+    /// Current `reloadable` settings, updated whenever the config file changes
+    ///
+    /// Handlers can get a clone of the receiver (e.g. as an `Extension`) and
+    /// `borrow()` the latest value. Without a config file, all the settings
+    /// have their default values.
+    pub fn reloadable_config<T>(&self) -> AppResult<watch::Receiver<T>>
+    where
+        T: DeserializeOwned + Send + Sync + 'static,
+    {
+        watch_reloadable(self.common_opts.config_file.as_deref())
+    }
+}
+
+fn watch_reloadable<T>(path: Option<&Path>) -> AppResult<watch::Receiver<T>>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+{
+    let path = match path {
+        Some(path) => path.to_owned(),
+        None => {
+            let (_tx, rx) = watch::channel(reloadable(&serde_json::Map::new())?);
+            return Ok(rx);
+        }
+    };
+
+    let mut last_modified = modified(&path);
+    let (tx, rx) = watch::channel(reloadable(&load(&path)?)?);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+            if tx.is_closed() {
+                return;
+            }
+
+            let current = modified(&path);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            // keep the previous settings if the new ones are broken
+            match load(&path).and_then(|file| reloadable(&file)) {
+                Ok(settings) => {
+                    info!(path = %path.display(), "Config reloaded");
+                    let _ = tx.send(settings);
+                }
+                Err(e) => warn!(path = %path.display(), "Failed to reload config: {e:?}"),
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn values(file: serde_json::Value) -> AppResult<Vec<(String, String)>> {
+        match file {
+            serde_json::Value::Object(file) => option_values(Path::new("app.toml"), &file),
+            _ => unreachable!("config file is a table"),
+        }
+    }
+
+    #[test]
+    fn option_values_as_command_line_values() {
+        let values = values(json!({
+            "listen": 8000,
+            "load-shed": true,
+            "log-format": "json",
+            "reloadable": {"greeting": "hi"},
+        }))
+        .unwrap();
+
+        assert_eq!(
+            values,
+            [
+                ("listen".to_owned(), "8000".to_owned()),
+                ("load-shed".to_owned(), "true".to_owned()),
+                ("log-format".to_owned(), "json".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn option_values_must_be_scalars() {
+        assert!(values(json!({"listen": [8000]})).is_err());
+        assert!(values(json!({"limits": {"rate-limit": 10}})).is_err());
+    }
+}
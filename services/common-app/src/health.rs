@@ -0,0 +1,112 @@
+//! Liveness (`/healthz`) and readiness (`/readyz`) probes
+//!
+//! Liveness only tells that the process is up and serving. Readiness runs
+//! all the checks registered with [`crate::AppBuilder::add_readiness_check`]
+//! and starts failing as soon as the shutdown signal is received, so
+//! Kubernetes stops routing new traffic to the pod before it goes away.
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use axum::{http::StatusCode, routing::get, Extension, Router};
+use tracing::warn;
+
+use crate::AppResult;
+
+pub const LIVENESS_PATH: &str = "/healthz";
+pub const READINESS_PATH: &str = "/readyz";
+
+type CheckFuture = Pin<Box<dyn Future<Output = AppResult<()>> + Send>>;
+
+#[derive(Clone)]
+pub(crate) struct ReadinessCheck {
+    name: String,
+    check: Arc<dyn Fn() -> CheckFuture + Send + Sync>,
+}
+
+impl ReadinessCheck {
+    pub(crate) fn new<F, Fut>(name: String, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AppResult<()>> + Send + 'static,
+    {
+        Self {
+            name,
+            check: Arc::new(move || Box::pin(check())),
+        }
+    }
+}
+
+impl fmt::Debug for ReadinessCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadinessCheck")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Health {
+    shutting_down: AtomicBool,
+    checks: Vec<ReadinessCheck>,
+}
+
+impl Health {
+    pub(crate) fn new(checks: Vec<ReadinessCheck>) -> Self {
+        Self {
+            shutting_down: AtomicBool::new(false),
+            checks,
+        }
+    }
+
+    pub(crate) fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Run all the checks; returns one line per check
+    async fn check_ready(&self) -> (bool, String) {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return (false, "shutting down\n".into());
+        }
+
+        let mut ready = true;
+        let mut body = String::new();
+        for check in &self.checks {
+            match (check.check)().await {
+                Ok(()) => body.push_str(&format!("{}: ok\n", check.name)),
+                Err(e) => {
+                    warn!(check = %check.name, "Readiness check failed: {e:?}");
+                    ready = false;
+                    body.push_str(&format!("{}: failed\n", check.name));
+                }
+            }
+        }
+        if ready {
+            body.push_str("ready\n");
+        }
+        (ready, body)
+    }
+
+    pub(crate) fn router(self: Arc<Self>) -> Router {
+        Router::new()
+            .route(LIVENESS_PATH, get(|| async { "ok\n" }))
+            .route(READINESS_PATH, get(readiness_handler))
+            .layer(Extension(self))
+    }
+}
+
+async fn readiness_handler(Extension(health): Extension<Arc<Health>>) -> (StatusCode, String) {
+    let (ready, body) = health.check_ready().await;
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, body)
+}
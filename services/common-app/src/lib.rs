@@ -1,17 +1,31 @@
 use clap::Args;
-use std::io;
+use common_tracing::TracingGuard;
+use error_stack::ResultExt;
+use std::{collections::BTreeMap, future::Future};
 
 use tokio::signal;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
 
 use thiserror::Error;
 
-use crate::opts::Opts;
+use crate::{health::ReadinessCheck, opts::Opts, tasks::BackgroundTask};
 
+mod admin;
+pub mod config;
+#[cfg(feature = "db")]
+pub mod db;
+mod health;
+mod metrics;
 mod opts;
+mod tasks;
 
 pub mod axum;
 pub use self::axum::*;
+pub use self::{
+    health::{LIVENESS_PATH, READINESS_PATH},
+    metrics::{Metrics, METRICS_PATH},
+};
 
 // make sure it matches `Opts`
 pub const DEFAULT_LISTEN_PORT: u16 = 3000;
@@ -22,38 +36,128 @@ pub struct NoOpts;
 #[derive(Debug)]
 pub struct AppBuilder<O> {
     common_opts: O,
+    readiness_checks: Vec<ReadinessCheck>,
+    tasks: Vec<BackgroundTask>,
+    route_limits: BTreeMap<String, RouteLimits>,
+    metrics: Metrics,
+    tracing_guard: Option<TracingGuard>,
+    /// Effective options, for `/debug/config`
+    config: serde_json::Value,
+    /// Run by `connect_database` and the `migrate` subcommand
+    #[cfg(feature = "db")]
+    migrator: Option<&'static sqlx::migrate::Migrator>,
 }
 
 impl AppBuilder<NoOpts> {
     pub fn new() -> Self {
-        tracing_subscriber::registry()
-            .with(tracing_subscriber::EnvFilter::new(
-                std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
-            ))
-            .with(tracing_subscriber::fmt::layer().with_writer(io::stderr))
-            .init();
-
         AppBuilder {
             common_opts: NoOpts,
+            readiness_checks: vec![],
+            tasks: vec![],
+            route_limits: BTreeMap::new(),
+            metrics: Metrics::new(),
+            tracing_guard: None,
+            config: serde_json::Value::Null,
+            #[cfg(feature = "db")]
+            migrator: None,
         }
     }
 
-    pub fn parse_opts<AppOpts>(self) -> (AppBuilder<opts::CommonOpts>, AppOpts)
+    /// Database migrations of the app, e.g. `static MIGRATOR: Migrator = sqlx::migrate!();`
+    ///
+    /// Needs to be set before `parse_opts`, which runs them (and exits) on `migrate`.
+    #[cfg(feature = "db")]
+    pub fn set_migrator(mut self, migrator: &'static sqlx::migrate::Migrator) -> Self {
+        self.migrator = Some(migrator);
+        self
+    }
+
+    /// Parse the command line and set up logging/tracing accordingly
+    pub fn parse_opts<AppOpts>(self) -> AppResult<(AppBuilder<opts::CommonOpts>, AppOpts)>
     where
         AppOpts: clap::FromArgMatches + clap::Args,
     {
-        let opts = Opts::from_args();
-        (
+        let (opts, config) = Opts::<AppOpts>::from_args_with_config()?;
+        let tracing_guard = common_tracing::init(&opts.common_opts().tracing, &app_name())
+            .change_context(AppError)?;
+
+        let (common_opts, app_opts) = match opts {
+            opts::ParsedOpts::App {
+                common_opts,
+                app_opts,
+            } => (common_opts, app_opts),
+            #[cfg(feature = "db")]
+            opts::ParsedOpts::Command {
+                common_opts,
+                command: db::Command::Migrate,
+            } => {
+                db::migrate(&common_opts.db, self.migrator)?;
+                drop(tracing_guard);
+                std::process::exit(0);
+            }
+        };
+
+        Ok((
             AppBuilder {
-                common_opts: opts.common_opts,
+                common_opts,
+                readiness_checks: self.readiness_checks,
+                tasks: self.tasks,
+                route_limits: self.route_limits,
+                metrics: self.metrics,
+                tracing_guard: Some(tracing_guard),
+                config,
+                #[cfg(feature = "db")]
+                migrator: self.migrator,
             },
-            opts.app_opts,
-        )
+            app_opts,
+        ))
     }
 
     // NOTE: there's also `run_axum` in `mod axum`
 }
 
+impl<O> AppBuilder<O> {
+    /// Register a check that has to pass for the app to report being ready
+    ///
+    /// All the checks are run on every `/readyz` request, so they should be cheap
+    /// (e.g. ping the database, not query it).
+    pub fn add_readiness_check<F, Fut>(mut self, name: impl Into<String>, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AppResult<()>> + Send + 'static,
+    {
+        self.readiness_checks
+            .push(ReadinessCheck::new(name.into(), check));
+        self
+    }
+
+    /// Register a task to run in the background while the app is serving
+    ///
+    /// The task should return soon after `cancel` is cancelled, on shutdown.
+    /// Returning an error shuts the app down.
+    pub fn spawn_task<F, Fut>(mut self, name: impl Into<String>, task: F) -> Self
+    where
+        F: Fn(CancellationToken) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AppResult<()>> + Send + 'static,
+    {
+        self.tasks.push(BackgroundTask::new(name.into(), task));
+        self
+    }
+
+    /// Override default limits for a route
+    ///
+    /// `route` is the path as passed to `Router::route` (e.g. `/users/:id`).
+    pub fn set_route_limits(mut self, route: impl Into<String>, limits: RouteLimits) -> Self {
+        self.route_limits.insert(route.into(), limits);
+        self
+    }
+
+    /// Metrics served on `/metrics`; register app-specific metrics here
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
 impl Default for AppBuilder<NoOpts> {
     fn default() -> Self {
         Self::new()
@@ -66,6 +170,16 @@ pub struct AppError;
 
 pub type AppResult<T> = error_stack::Result<T, AppError>;
 
+/// Name of the app binary, used to identify exported traces
+fn app_name() -> String {
+    std::env::args_os()
+        .next()
+        .as_ref()
+        .and_then(|arg0| std::path::Path::new(arg0).file_stem())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "common-app".into())
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -89,5 +203,5 @@ async fn shutdown_signal() {
                         _ = terminate => {},
     }
 
-    println!("signal received, starting graceful shutdown");
+    info!("signal received");
 }
@@ -0,0 +1,154 @@
+//! Prometheus metrics, served on `/metrics`
+//!
+//! HTTP metrics are recorded per matched route (not per raw path, which
+//! would explode the label cardinality). Services can register their own
+//! metrics in [`Metrics::registry`].
+use std::{fmt, time::Instant};
+
+use axum::{
+    extract::MatchedPath,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+    routing::get,
+    Extension, Router,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+pub const METRICS_PATH: &str = "/metrics";
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests_total: IntCounterVec,
+    request_duration: HistogramVec,
+    requests_in_flight: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests_total = IntCounterVec::new(
+            Opts::new("http_requests_total", "Total number of HTTP requests"),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency in seconds",
+            ),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let requests_in_flight = IntGaugeVec::new(
+            Opts::new(
+                "http_requests_in_flight",
+                "Number of HTTP requests being served",
+            ),
+            &["method", "route"],
+        )
+        .expect("valid metric");
+
+        registry
+            .register(Box::new(requests_total.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(request_duration.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(requests_in_flight.clone()))
+            .expect("unique metric");
+
+        Self {
+            registry,
+            requests_total,
+            request_duration,
+            requests_in_flight,
+        }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Record metrics of a request; meant for `Router::route_layer`
+    pub(crate) async fn track<B>(self, req: Request<B>, next: Next<B>) -> impl IntoResponse {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_owned())
+            .unwrap_or_else(|| "unmatched".into());
+
+        let in_flight = InFlightGuard::new(
+            self.requests_in_flight
+                .with_label_values(&[&method, &route]),
+        );
+        let response = next.run(req).await;
+        drop(in_flight);
+
+        let status = response.status().as_u16().to_string();
+        self.requests_total
+            .with_label_values(&[&method, &route, &status])
+            .inc();
+        self.request_duration
+            .with_label_values(&[&method, &route, &status])
+            .observe(start.elapsed().as_secs_f64());
+
+        response
+    }
+
+    pub(crate) fn router(&self) -> Router {
+        Router::new()
+            .route(METRICS_PATH, get(metrics_handler))
+            .layer(Extension(self.clone()))
+    }
+}
+
+/// Decrements the in-flight gauge also when the request future is dropped
+struct InFlightGuard(IntGauge);
+
+impl InFlightGuard {
+    fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn metrics_handler(Extension(metrics): Extension<Metrics>) -> impl IntoResponse {
+    let mut buf = vec![];
+    let encoder = TextEncoder::new();
+    match encoder.encode(&metrics.registry.gather(), &mut buf) {
+        Ok(()) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+            buf,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
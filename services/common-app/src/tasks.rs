@@ -0,0 +1,218 @@
+//! Background tasks (queue processors, pollers, ...) running next to the server
+//!
+//! Registered with [`crate::AppBuilder::spawn_task`] and started by `run_axum`.
+//! On shutdown the server stops accepting requests and finishes the in-flight
+//! ones first, then the tasks are cancelled (through their
+//! [`CancellationToken`]) and awaited, all within `--shutdown-grace-period`.
+//! A failing task shuts the whole app down.
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+
+use error_stack::Report;
+use tokio::{task::JoinHandle, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::{AppError, AppResult};
+
+type TaskFuture = Pin<Box<dyn Future<Output = AppResult<()>> + Send>>;
+
+#[derive(Clone)]
+pub(crate) struct BackgroundTask {
+    name: String,
+    run: Arc<dyn Fn(CancellationToken) -> TaskFuture + Send + Sync>,
+}
+
+impl BackgroundTask {
+    pub(crate) fn new<F, Fut>(name: String, run: F) -> Self
+    where
+        F: Fn(CancellationToken) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AppResult<()>> + Send + 'static,
+    {
+        Self {
+            name,
+            run: Arc::new(move |cancel| Box::pin(run(cancel))),
+        }
+    }
+}
+
+impl fmt::Debug for BackgroundTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackgroundTask")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+pub(crate) struct RunningTasks {
+    cancel: CancellationToken,
+    handles: Vec<(String, JoinHandle<AppResult<()>>)>,
+}
+
+impl RunningTasks {
+    /// Spawn all the tasks; `shutdown` is cancelled if any of them fails
+    pub(crate) fn start(tasks: &[BackgroundTask], shutdown: CancellationToken) -> Self {
+        let cancel = CancellationToken::new();
+        let handles = tasks
+            .iter()
+            .map(|task| {
+                let name = task.name.clone();
+                let future = (task.run)(cancel.clone());
+                let shutdown = shutdown.clone();
+                let handle = tokio::spawn({
+                    let name = name.clone();
+                    async move {
+                        let res = future.await;
+                        match &res {
+                            Ok(()) => info!(task = %name, "Background task finished"),
+                            Err(e) => {
+                                error!(task = %name, "Background task failed: {e:?}");
+                                shutdown.cancel();
+                            }
+                        }
+                        res
+                    }
+                });
+                info!(task = %name, "Background task started");
+                (name, handle)
+            })
+            .collect();
+
+        Self { cancel, handles }
+    }
+
+    /// Cancel all the tasks and wait for them until `deadline`
+    ///
+    /// Fails if any of them failed, panicked or did not finish in time.
+    pub(crate) async fn shutdown(self, deadline: Instant) -> AppResult<()> {
+        self.cancel.cancel();
+
+        let mut failed = vec![];
+        for (name, mut handle) in self.handles {
+            match tokio::time::timeout_at(deadline, &mut handle).await {
+                Ok(Ok(Ok(()))) => {}
+                // already logged
+                Ok(Ok(Err(_))) => failed.push(name),
+                Ok(Err(e)) => {
+                    error!(task = %name, "Background task panicked: {e}");
+                    failed.push(name);
+                }
+                Err(_) => {
+                    warn!(task = %name, "Background task did not stop in time, aborting");
+                    handle.abort();
+                    failed.push(name);
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Report::new(AppError)
+                .attach_printable(format!("Background tasks failed: {}", failed.join(", "))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use super::*;
+
+    type Events = Arc<Mutex<Vec<String>>>;
+
+    /// Task stopping `stop_delay` after being cancelled
+    fn task(name: &str, events: &Events, stop_delay: Duration) -> BackgroundTask {
+        let events = events.clone();
+        let name = name.to_owned();
+        BackgroundTask::new(name.clone(), move |cancel| {
+            let (events, name) = (events.clone(), name.clone());
+            async move {
+                cancel.cancelled().await;
+                events.lock().unwrap().push(format!("{name} cancelled"));
+                tokio::time::sleep(stop_delay).await;
+                events.lock().unwrap().push(format!("{name} stopped"));
+                Ok(())
+            }
+        })
+    }
+
+    fn events(events: &Events) -> Vec<String> {
+        events.lock().unwrap().clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_and_waits_for_all_tasks() {
+        let log = Events::default();
+        let shutdown = CancellationToken::new();
+        let tasks = RunningTasks::start(
+            &[
+                task("slow", &log, Duration::from_secs(2)),
+                task("fast", &log, Duration::ZERO),
+            ],
+            shutdown.clone(),
+        );
+
+        // running until the shutdown
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(events(&log).is_empty());
+
+        let start = Instant::now();
+        tasks
+            .shutdown(start + Duration::from_secs(30))
+            .await
+            .unwrap();
+
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(
+            events(&log),
+            [
+                "slow cancelled",
+                "fast cancelled",
+                "fast stopped",
+                "slow stopped"
+            ]
+        );
+        assert!(!shutdown.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_gives_up_on_tasks_ignoring_cancellation() {
+        let log = Events::default();
+        let stubborn = BackgroundTask::new("stubborn".into(), |_cancel| async {
+            std::future::pending::<()>().await;
+            Ok(())
+        });
+        let tasks = RunningTasks::start(
+            &[stubborn, task("polite", &log, Duration::from_secs(1))],
+            CancellationToken::new(),
+        );
+        tokio::task::yield_now().await;
+
+        let start = Instant::now();
+        let res = tasks.shutdown(start + Duration::from_secs(5)).await;
+
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        let err = res.unwrap_err();
+        assert!(format!("{err:?}").contains("Background tasks failed: stubborn"));
+        // the deadline is shared, but others are still waited for
+        assert_eq!(events(&log), ["polite cancelled", "polite stopped"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_task_requests_shutdown() {
+        let failing = BackgroundTask::new("failing".into(), |_cancel| async {
+            Err(Report::new(AppError))
+        });
+        let shutdown = CancellationToken::new();
+        let tasks = RunningTasks::start(&[failing], shutdown.clone());
+
+        tokio::time::timeout(Duration::from_secs(1), shutdown.cancelled())
+            .await
+            .expect("shutdown requested");
+        assert!(tasks
+            .shutdown(Instant::now() + Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! The rustshop environment resources are generated for
+use error_stack::Report;
+use rustshop_env::{ContextYaml, Env, EnvRoot};
+use tracing::debug;
+
+use crate::{opts::CommonOpts, GenError, GenResult};
+
+#[derive(Debug, Clone)]
+pub struct GenEnv {
+    pub shop_name: String,
+    pub shop_domain: String,
+    pub account: Option<String>,
+    pub cluster: Option<String>,
+    /// Domain of the cluster (its Route53 zone)
+    pub cluster_domain: Option<String>,
+    /// `kubectl` context of the cluster
+    pub kube_ctx: Option<String>,
+    pub namespace: Option<String>,
+}
+
+impl GenEnv {
+    /// Load the current rustshop context, with `--account`/`--cluster` applied
+    ///
+    /// Returns `None` outside of a rustshop environment (`RUSTSHOP_ROOT` not set),
+    /// unless an account or cluster was explicitly requested.
+    pub fn load(opts: &CommonOpts) -> GenResult<Option<Self>> {
+        if EnvRoot::load_path().is_err() && opts.account.is_none() && opts.cluster.is_none() {
+            debug!("Not in a rustshop environment");
+            return Ok(None);
+        }
+
+        // `rustshop-env` uses a newer `error-stack`, so its report can't be a
+        // context here; keep it whole as an attachment
+        let env = Env::load().map_err(|e| {
+            Report::new(GenError)
+                .attach_printable("Loading rustshop env failed")
+                .attach_printable(e)
+        })?;
+
+        let mut overrides = ContextYaml::from_env();
+        if opts.account.is_some() {
+            overrides.account = opts.account.clone();
+        }
+        if opts.cluster.is_some() {
+            overrides.cluster = opts.cluster.clone();
+        }
+
+        let context = env.get_context_with_overrides(overrides).map_err(|e| {
+            Report::new(GenError)
+                .attach_printable("Invalid rustshop context")
+                .attach_printable(e)
+        })?;
+
+        let shop = env.shop_cfg();
+        Ok(Some(Self {
+            shop_name: shop.name.clone(),
+            shop_domain: shop.domain.clone(),
+            account: context.account.map(|(name, _)| name),
+            cluster_domain: context
+                .cluster
+                .as_ref()
+                .map(|(_, cfg)| cfg.shop.domain.clone()),
+            kube_ctx: context
+                .cluster
+                .as_ref()
+                .map(|(_, cfg)| cfg.user.kube_ctx.clone()),
+            cluster: context.cluster.map(|(name, _)| name),
+            namespace: context.namespace,
+        }))
+    }
+
+    /// Cluster domain, if a cluster is selected, shop domain otherwise
+    pub fn domain(&self) -> &str {
+        self.cluster_domain.as_deref().unwrap_or(&self.shop_domain)
+    }
+}
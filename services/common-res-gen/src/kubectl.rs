@@ -0,0 +1,450 @@
+//! Applying generated resources to the cluster
+//!
+//! Goes through `kubectl`, explicitly targeting the rustshop cluster the
+//! resources were generated for (see [`Target`]). When `kubectl` is wrapped
+//! by `rustshop`, the wrapper is told the same account and cluster, so it
+//! uses the right credentials.
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use error_stack::{IntoReport, ResultExt};
+use rustshop_env::ContextYaml;
+use tracing::{debug, info};
+
+use crate::{output, GenEnv, GenError, GenResult, OutputFormat, Resource};
+
+/// Label marking which generator owns a resource, used for pruning
+pub const GENERATOR_LABEL: &str = "rustshop/generator";
+
+/// Kinds we look at when pruning (and their `kubectl` resource names), in
+/// addition to kinds being generated
+const PRUNABLE_KINDS: &[(&str, &str)] = &[
+    ("ConfigMap", "configmaps"),
+    ("CronJob", "cronjobs.batch"),
+    ("Deployment", "deployments.apps"),
+    (
+        "HorizontalPodAutoscaler",
+        "horizontalpodautoscalers.autoscaling",
+    ),
+    ("Ingress", "ingresses.networking.k8s.io"),
+    ("Job", "jobs.batch"),
+    ("NetworkPolicy", "networkpolicies.networking.k8s.io"),
+    ("PodDisruptionBudget", "poddisruptionbudgets.policy"),
+    ("Secret", "secrets"),
+    ("ServiceAccount", "serviceaccounts"),
+    ("Service", "services"),
+];
+
+/// The cluster (and namespace) `kubectl` runs against
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Target {
+    account: Option<String>,
+    cluster: Option<String>,
+    kube_ctx: Option<String>,
+    namespace: Option<String>,
+}
+
+impl Target {
+    /// Target the rustshop cluster of `env`
+    ///
+    /// Outside of a rustshop environment, `kubectl`'s current context is used.
+    pub(crate) fn new(env: Option<&GenEnv>) -> GenResult<Self> {
+        let env = match env {
+            Some(env) => env,
+            None => return Ok(Self::default()),
+        };
+        if env.kube_ctx.is_none() {
+            return Err(error_stack::Report::new(GenError).attach_printable(
+                "No rustshop cluster selected (switch to one, or use `--cluster`)",
+            ));
+        }
+
+        Ok(Self {
+            account: env.account.clone(),
+            cluster: env.cluster.clone(),
+            kube_ctx: env.kube_ctx.clone(),
+            namespace: env.namespace.clone(),
+        })
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(kube_ctx) = self.kube_ctx.as_ref() {
+            args.extend(["--context".to_owned(), kube_ctx.clone()]);
+        }
+        if let Some(namespace) = self.namespace.as_ref() {
+            args.extend(["--namespace".to_owned(), namespace.clone()]);
+        }
+        args
+    }
+
+    /// Namespace resources without an explicit one end up in
+    ///
+    /// Without a rustshop namespace, it's the one of the `kubectl` context.
+    fn default_namespace(&self) -> GenResult<String> {
+        if let Some(namespace) = self.namespace.as_ref() {
+            return Ok(namespace.clone());
+        }
+
+        let output = self
+            .kubectl()
+            .args(["config", "view", "--minify", "-o", "jsonpath={..namespace}"])
+            .stderr(Stdio::inherit())
+            .output()
+            .report()
+            .change_context(GenError)?;
+        if !output.status.success() {
+            return Err(
+                error_stack::Report::new(GenError).attach_printable("kubectl config view failed")
+            );
+        }
+
+        let namespace = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        Ok(if namespace.is_empty() {
+            "default".to_owned()
+        } else {
+            namespace
+        })
+    }
+
+    fn kubectl(&self) -> Command {
+        let mut cmd = Command::new("kubectl");
+        cmd.args(self.args());
+        for (name, value) in [
+            (ContextYaml::ACCOUNT_ENV_NAME, &self.account),
+            (ContextYaml::CLUSTER_ENV_NAME, &self.cluster),
+            (ContextYaml::NAMESPACE_ENV_NAME, &self.namespace),
+        ] {
+            if let Some(value) = value {
+                cmd.env(name, value);
+            }
+        }
+        cmd
+    }
+}
+
+/// Run `kubectl` with the resources as a yaml stream on its stdin
+///
+/// Returns `kubectl`'s exit code.
+fn run_with_resources(mut cmd: Command, resources: &[Resource]) -> GenResult<i32> {
+    let mut input = vec![];
+    output::write_stream(resources, OutputFormat::Yaml, &mut input)?;
+
+    cmd.args(["-f", "-"]).stdin(Stdio::piped());
+    debug!("Running: {cmd:?}");
+    let mut child = cmd.spawn().report().change_context(GenError)?;
+    child
+        .stdin
+        .take()
+        .expect("stdin piped")
+        .write_all(&input)
+        .report()
+        .change_context(GenError)?;
+    let status = child.wait().report().change_context(GenError)?;
+
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Server-side apply all the resources
+pub(crate) fn apply(target: &Target, resources: &[Resource], field_manager: &str) -> GenResult<()> {
+    let mut cmd = target.kubectl();
+    cmd.args(["apply", "--server-side", "--field-manager", field_manager]);
+
+    match run_with_resources(cmd, resources)? {
+        0 => Ok(()),
+        code => Err(error_stack::Report::new(GenError)
+            .attach_printable(format!("kubectl apply failed with exit code: {code}"))),
+    }
+}
+
+/// Show the difference between the cluster and the resources
+///
+/// Returns `true` if there are any differences.
+pub(crate) fn diff(
+    target: &Target,
+    resources: &[Resource],
+    field_manager: &str,
+) -> GenResult<bool> {
+    let mut cmd = target.kubectl();
+    cmd.args(["diff", "--server-side", "--field-manager", field_manager]);
+
+    diff_outcome(run_with_resources(cmd, resources)?)
+}
+
+/// `kubectl diff` exits with 1 on differences, and >1 on errors
+fn diff_outcome(code: i32) -> GenResult<bool> {
+    match code {
+        0 => Ok(false),
+        1 => Ok(true),
+        code => Err(error_stack::Report::new(GenError)
+            .attach_printable(format!("kubectl diff failed with exit code: {code}"))),
+    }
+}
+
+/// `kubectl` resource names of all the kinds that might need pruning
+fn prune_kinds(resources: &[Resource]) -> String {
+    let mut kinds: BTreeSet<String> = PRUNABLE_KINDS
+        .iter()
+        .map(|(_kind, name)| name.to_string())
+        .collect();
+    for resource in resources {
+        if !PRUNABLE_KINDS
+            .iter()
+            .any(|(kind, _name)| *kind == resource.kind())
+        {
+            kinds.insert(resource.kind().to_lowercase());
+        }
+    }
+    kinds.into_iter().collect::<Vec<_>>().join(",")
+}
+
+/// An object found in the cluster
+#[derive(Debug, PartialEq, Eq)]
+struct Existing {
+    kind: String,
+    name: String,
+    namespace: Option<String>,
+}
+
+/// Objects from a `kubectl get -o json` list that are not generated anymore
+///
+/// Generated resources without an explicit namespace are matched against
+/// `default_namespace`.
+fn prune_candidates(
+    resources: &[Resource],
+    default_namespace: &str,
+    existing: &serde_json::Value,
+) -> Vec<Existing> {
+    let items = existing
+        .get("items")
+        .and_then(|items| items.as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    items
+        .iter()
+        .map(|item| {
+            let metadata = item.get("metadata");
+            let str_field = |value: Option<&serde_json::Value>| {
+                value.and_then(|v| v.as_str()).map(ToOwned::to_owned)
+            };
+            Existing {
+                kind: str_field(item.get("kind")).unwrap_or_default(),
+                name: str_field(metadata.and_then(|m| m.get("name"))).unwrap_or_default(),
+                namespace: str_field(metadata.and_then(|m| m.get("namespace"))),
+            }
+        })
+        .filter(|existing| {
+            !resources.iter().any(|r| {
+                r.kind() == existing.kind
+                    && r.name() == Some(existing.name.as_str())
+                    // cluster-scoped objects have no namespace
+                    && (existing.namespace.is_none()
+                        || existing.namespace.as_deref()
+                            == Some(r.namespace().unwrap_or(default_namespace)))
+            })
+        })
+        .collect()
+}
+
+/// Delete resources owned by `generator` that are not being generated anymore
+///
+/// Looks in all namespaces, so resources moved (or removed) from a namespace
+/// are cleaned up too.
+pub(crate) fn prune(
+    target: &Target,
+    resources: &[Resource],
+    generator: &str,
+    dry_run: bool,
+) -> GenResult<()> {
+    let default_namespace = target.default_namespace()?;
+    let output = target
+        .kubectl()
+        .args([
+            "get",
+            &prune_kinds(resources),
+            "--all-namespaces",
+            "--ignore-not-found",
+            "-l",
+            &format!("{GENERATOR_LABEL}={generator}"),
+            "-o",
+            "json",
+        ])
+        .stderr(Stdio::inherit())
+        .output()
+        .report()
+        .change_context(GenError)?;
+    if !output.status.success() {
+        return Err(error_stack::Report::new(GenError).attach_printable("kubectl get failed"));
+    }
+
+    let existing: serde_json::Value = if output.stdout.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_slice(&output.stdout)
+            .report()
+            .change_context(GenError)?
+    };
+
+    for Existing {
+        kind,
+        name,
+        namespace,
+    } in prune_candidates(resources, &default_namespace, &existing)
+    {
+        if dry_run {
+            info!(kind, name, ?namespace, "Would prune");
+            continue;
+        }
+
+        info!(kind, name, ?namespace, "Pruning");
+        let mut cmd = target.kubectl();
+        cmd.args(["delete", &format!("{kind}/{name}")]);
+        if let Some(namespace) = namespace.as_ref() {
+            cmd.args(["-n", namespace]);
+        }
+        let status = cmd.status().report().change_context(GenError)?;
+        if !status.success() {
+            return Err(error_stack::Report::new(GenError)
+                .attach_printable(format!("Deleting {kind}/{name} failed")));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{k8s, DynamicObject};
+
+    fn deployment(name: &str, namespace: Option<&str>) -> Resource {
+        Resource::new(&k8s::Deployment {
+            metadata: k8s::ObjectMeta {
+                name: Some(name.to_owned()),
+                namespace: namespace.map(ToOwned::to_owned),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn item(kind: &str, name: &str, namespace: &str) -> serde_json::Value {
+        json!({"kind": kind, "metadata": {"name": name, "namespace": namespace}})
+    }
+
+    fn cluster_item(kind: &str, name: &str) -> serde_json::Value {
+        json!({"kind": kind, "metadata": {"name": name}})
+    }
+
+    fn existing(kind: &str, name: &str, namespace: &str) -> Existing {
+        Existing {
+            kind: kind.to_owned(),
+            name: name.to_owned(),
+            namespace: Some(namespace.to_owned()),
+        }
+    }
+
+    #[test]
+    fn prunes_only_what_is_not_generated() {
+        let issuer = Resource::from_dynamic(&DynamicObject {
+            metadata: k8s::ObjectMeta {
+                name: Some("letsencrypt".to_owned()),
+                ..Default::default()
+            },
+            ..DynamicObject::new("cert-manager.io/v1", "ClusterIssuer")
+        });
+        let resources = [
+            deployment("app", None),
+            deployment("worker", Some("jobs")),
+            issuer,
+        ];
+        let cluster = json!({"items": [
+            item("Deployment", "app", "apps"),
+            item("Deployment", "worker", "jobs"),
+            cluster_item("ClusterIssuer", "letsencrypt"),
+            // removed from the generator
+            item("Deployment", "old", "apps"),
+            // same name, different kind
+            item("Service", "app", "apps"),
+            // same name, different explicit namespace
+            item("Deployment", "worker", "apps"),
+            // same name, but not in the default namespace
+            item("Deployment", "app", "jobs"),
+        ]});
+
+        assert_eq!(
+            prune_candidates(&resources, "apps", &cluster),
+            [
+                existing("Deployment", "old", "apps"),
+                existing("Service", "app", "apps"),
+                existing("Deployment", "worker", "apps"),
+                existing("Deployment", "app", "jobs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn prunes_nothing_when_nothing_found() {
+        let resources = [deployment("app", None)];
+        assert!(prune_candidates(&resources, "default", &serde_json::Value::Null).is_empty());
+        assert!(prune_candidates(&resources, "default", &json!({"items": []})).is_empty());
+    }
+
+    #[test]
+    fn prune_looks_at_generated_custom_kinds() {
+        let certificate =
+            Resource::from_dynamic(&DynamicObject::new("cert-manager.io/v1", "Certificate"));
+        let kinds = prune_kinds(&[deployment("app", None), certificate]);
+
+        assert!(kinds.split(',').any(|kind| kind == "certificate"));
+        assert!(kinds.split(',').any(|kind| kind == "deployments.apps"));
+        assert!(!kinds.split(',').any(|kind| kind == "deployment"));
+    }
+
+    #[test]
+    fn diff_exit_codes() {
+        assert!(!diff_outcome(0).unwrap());
+        assert!(diff_outcome(1).unwrap());
+        assert!(diff_outcome(2).is_err());
+    }
+
+    fn gen_env(kube_ctx: Option<&str>, namespace: Option<&str>) -> GenEnv {
+        GenEnv {
+            shop_name: "shop".into(),
+            shop_domain: "shop.com".into(),
+            account: Some("prod".into()),
+            cluster: kube_ctx.map(|_| "prod".into()),
+            cluster_domain: kube_ctx.map(|_| "prod.k8s.shop.com".into()),
+            kube_ctx: kube_ctx.map(ToOwned::to_owned),
+            namespace: namespace.map(ToOwned::to_owned),
+        }
+    }
+
+    #[test]
+    fn targets_the_generated_for_cluster() {
+        let target = Target::new(Some(&gen_env(Some("prod-ctx"), Some("apps")))).unwrap();
+        assert_eq!(
+            target.args(),
+            ["--context", "prod-ctx", "--namespace", "apps"]
+        );
+
+        let target = Target::new(Some(&gen_env(Some("prod-ctx"), None))).unwrap();
+        assert_eq!(target.args(), ["--context", "prod-ctx"]);
+    }
+
+    #[test]
+    fn refuses_account_without_cluster() {
+        assert!(Target::new(Some(&gen_env(None, None))).is_err());
+    }
+
+    #[test]
+    fn uses_current_context_outside_rustshop() {
+        assert!(Target::new(None).unwrap().args().is_empty());
+    }
+}
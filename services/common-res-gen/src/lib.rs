@@ -1,106 +1,363 @@
 #![feature(option_get_or_insert_default)]
 
 use amplify::{bmap, s};
-use derive_more::From;
-use error_stack::{IntoReport, Result, ResultExt};
+use error_stack::{Report, Result, ResultExt};
 use k8s_openapi::{
     api::{
-        apps::v1::DeploymentSpec,
-        core::v1::{Container, PodSpec, PodTemplateSpec, ServiceSpec},
+        apps::v1::{DeploymentSpec, DeploymentStrategy, RollingUpdateDeployment},
+        autoscaling::v2::{
+            CrossVersionObjectReference, HorizontalPodAutoscalerSpec, MetricSpec, MetricTarget,
+            ResourceMetricSource,
+        },
+        batch::v1::{CronJobSpec, JobSpec, JobTemplateSpec},
+        core::v1::{
+            Capabilities, Container, ContainerPort, EmptyDirVolumeSource, EnvVarSource,
+            HTTPGetAction, PodSecurityContext, PodSpec, PodTemplateSpec, Probe,
+            ResourceRequirements, SecretEnvSource, SecretKeySelector, SecurityContext, ServiceSpec,
+            Volume, VolumeMount,
+        },
+        networking::v1::{
+            HTTPIngressPath, HTTPIngressRuleValue, IngressBackend, IngressRule,
+            IngressServiceBackend, IngressSpec, IngressTLS, NetworkPolicyIngressRule,
+            NetworkPolicyPeer, NetworkPolicyPort, NetworkPolicySpec, ServiceBackendPort,
+        },
+        policy::v1::PodDisruptionBudgetSpec,
     },
-    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::ObjectMeta},
     DeepMerge,
 };
+use serde::{Deserialize, Serialize};
 use std::{
     borrow::Borrow,
     collections::{
         btree_map::Entry::{Occupied, Vacant},
         BTreeMap,
     },
-    io,
+    net::SocketAddr,
 };
 use thiserror::Error;
-use tracing::log::warn;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::log::{error, warn};
 
-pub use crate::opts::Opts;
+pub use crate::{
+    env::GenEnv,
+    kubectl::GENERATOR_LABEL,
+    opts::{Command, Opts, OutputFormat},
+};
+/// For generators adding kinds not re-exported in [`k8s`]
+pub use k8s_openapi;
 
+mod env;
+mod kubectl;
 mod opts;
+mod output;
+pub mod testing;
+pub mod validate;
 
 pub mod k8s {
+    pub use k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscaler;
+    pub use k8s_openapi::api::batch::v1::{CronJob, Job};
     pub use k8s_openapi::api::core::v1::ServicePort;
     pub use k8s_openapi::api::core::v1::ServiceSpec;
+    pub use k8s_openapi::api::core::v1::{ConfigMap, Secret, ServiceAccount};
+    pub use k8s_openapi::api::core::v1::{EnvFromSource, EnvVar, PodSpec};
+    pub use k8s_openapi::api::networking::v1::{Ingress, NetworkPolicy};
+    pub use k8s_openapi::api::policy::v1::PodDisruptionBudget;
     pub use k8s_openapi::api::{apps::v1::Deployment, core::v1::Service};
     pub use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
     pub use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
     pub use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
 }
 
-/// A resource that we allow to be generated
-#[derive(From, Debug, Clone)]
-pub enum Resource {
-    Deployment(k8s::Deployment),
-    Service(k8s::Service),
+/// Port standard services listen on (`common_app::DEFAULT_LISTEN_PORT`)
+pub const STANDARD_PORT: i32 = 3000;
+/// Liveness endpoint of standard services
+pub const STANDARD_LIVENESS_PATH: &str = "/healthz";
+/// Readiness endpoint of standard services
+pub const STANDARD_READINESS_PATH: &str = "/readyz";
+/// Env var moving health checks of standard services to a separate admin listener
+/// (`common_app::opts::Opts::admin_listen`)
+pub const STANDARD_ADMIN_LISTEN_ENV: &str = "ADMIN_LISTEN";
+/// Non-root user standard deployment containers run as (`nobody`)
+pub const STANDARD_RUN_AS_USER: i64 = 65534;
+
+/// Default external-secrets `ClusterSecretStore` backed by the account's AWS
+/// Secrets Manager (`--secret-store`)
+pub const EXTERNAL_SECRETS_STORE: &str = "aws-secrets-manager";
+const EXTERNAL_SECRET_API_VERSION: &str = "external-secrets.io/v1beta1";
+
+/// Default cert-manager `ClusterIssuer` used for public endpoints (`--cluster-issuer`)
+pub const CERT_MANAGER_CLUSTER_ISSUER: &str = "letsencrypt";
+
+/// A generated resource
+///
+/// Any k8s object (including custom resources, see [`DynamicObject`]),
+/// already converted to its serialized form.
+#[derive(Debug, Clone)]
+pub struct Resource {
+    api_version: String,
+    kind: String,
+    value: serde_json::Value,
+}
+
+impl Resource {
+    pub fn new<T>(resource: &T) -> Self
+    where
+        T: k8s_openapi::Resource + serde::Serialize,
+    {
+        Self {
+            api_version: T::API_VERSION.to_owned(),
+            kind: T::KIND.to_owned(),
+            value: serde_json::to_value(resource).expect("k8s objects always serialize"),
+        }
+    }
+
+    pub fn from_dynamic(object: &DynamicObject) -> Self {
+        Self {
+            api_version: object.api_version.clone(),
+            kind: object.kind.clone(),
+            value: serde_json::to_value(object).expect("k8s objects always serialize"),
+        }
+    }
+
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.value.get("metadata")?.get("name")?.as_str()
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.value.get("metadata")?.get("namespace")?.as_str()
+    }
+
+    /// Convert back to a typed k8s object, if it is one
+    pub fn to_typed<T>(&self) -> Option<T>
+    where
+        T: k8s_openapi::Resource + serde::de::DeserializeOwned,
+    {
+        if self.api_version != T::API_VERSION || self.kind != T::KIND {
+            return None;
+        }
+        serde_json::from_value(self.value.clone()).ok()
+    }
 }
 
-// Maybe use https://docs.rs/impl-enum instead?
 impl serde::Serialize for Resource {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        match self {
-            Resource::Deployment(r) => r.serialize(serializer),
-            Resource::Service(r) => r.serialize(serializer),
+        self.value.serialize(serializer)
+    }
+}
+
+/// An object of a kind `k8s-openapi` doesn't know about, like a CRD
+///
+/// Everything except `apiVersion`, `kind` and `metadata` (usually just
+/// `spec`) goes into `data`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DynamicObject {
+    #[serde(rename = "apiVersion")]
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default)]
+    pub metadata: ObjectMeta,
+    #[serde(flatten)]
+    pub data: serde_json::Map<String, serde_json::Value>,
+}
+
+impl DynamicObject {
+    pub fn new(api_version: &str, kind: &str) -> Self {
+        Self {
+            api_version: api_version.to_owned(),
+            kind: kind.to_owned(),
+            ..Default::default()
         }
     }
+
+    /// Set a top-level field, like `spec`
+    pub fn set(&mut self, field: &str, value: serde_json::Value) -> &mut Self {
+        self.data.insert(field.to_owned(), value);
+        self
+    }
 }
 
 pub trait Generator {
+    /// Stable name of the generator, e.g. `env!("CARGO_PKG_NAME")`
+    ///
+    /// Marks the resources it owns (see [`GENERATOR_LABEL`]), so changing it
+    /// makes `prune` lose track of them.
+    const NAME: &'static str;
+
     type Opts: clap::Args;
 
     fn generate(&mut self, ctx: &mut GenContext, opts: &Self::Opts) -> GenResult<()>;
 }
 
 pub struct GenContext {
-    #[allow(unused)]
     opts: opts::CommonOpts,
+    env: Option<GenEnv>,
     resources: Vec<Resource>,
     common_labels: LabelSet,
+    common_annotations: BTreeMap<String, String>,
+    policy_rules: Vec<Box<dyn validate::PolicyRule>>,
 }
 
 impl GenContext {
-    fn new(opts: opts::CommonOpts) -> GenContext {
+    fn new(opts: opts::CommonOpts, env: Option<GenEnv>, generator_name: &str) -> GenContext {
+        let shop_name = env
+            .as_ref()
+            .map(|env| env.shop_name.as_str())
+            .unwrap_or("rustshop");
+        let common_labels = LabelSet::new()
+            .insert("shop", shop_name)
+            .insert(GENERATOR_LABEL, generator_name);
+
         Self {
             opts,
+            env,
             resources: vec![],
-            common_labels: LabelSet::new().insert("shop", "rustshop").to_owned(),
+            common_labels,
+            common_annotations: BTreeMap::new(),
+            policy_rules: validate::default_rules(),
         }
     }
 
-    pub fn add_plain_service<'ctx>(
+    /// Additional rule generated resources must pass (see [`validate`])
+    pub fn add_policy_rule(&mut self, rule: impl validate::PolicyRule + 'static) -> &mut Self {
+        self.policy_rules.push(Box::new(rule));
+        self
+    }
+
+    /// The rustshop environment, if generating inside one
+    pub fn env(&self) -> Option<&GenEnv> {
+        self.env.as_ref()
+    }
+
+    pub fn shop_name(&self) -> Option<&str> {
+        self.env.as_ref().map(|env| env.shop_name.as_str())
+    }
+
+    pub fn account(&self) -> Option<&str> {
+        self.env.as_ref()?.account.as_deref()
+    }
+
+    pub fn cluster(&self) -> Option<&str> {
+        self.env.as_ref()?.cluster.as_deref()
+    }
+
+    /// See [`GenEnv::domain`]
+    pub fn domain(&self) -> Option<&str> {
+        self.env.as_ref().map(GenEnv::domain)
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.env.as_ref()?.namespace.as_deref()
+    }
+
+    /// Is the current account `name`, e.g. to use more replicas in `prod`
+    pub fn is_account(&self, name: &str) -> bool {
+        self.account() == Some(name)
+    }
+
+    /// Annotation to be added to every generated resource
+    pub fn add_common_annotation(&mut self, name: &str, value: &str) -> &mut Self {
+        self.common_annotations
+            .insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Merge common labels and annotations, without overwriting existing ones
+    fn apply_common_metadata(&self, metadata: &mut ObjectMeta) {
+        for (src, dst) in [
+            (&self.common_labels.0, &mut metadata.labels),
+            (&self.common_annotations, &mut metadata.annotations),
+        ] {
+            if src.is_empty() {
+                continue;
+            }
+            let dst = dst.get_or_insert_default();
+            for (name, value) in src {
+                dst.entry(name.to_owned())
+                    .or_insert_with(|| value.to_owned());
+            }
+        }
+    }
+
+    /// Add any k8s object
+    pub fn add_resource<T>(&mut self, mut resource: T) -> &mut Self
+    where
+        T: k8s_openapi::Resource + k8s_openapi::Metadata<Ty = ObjectMeta> + serde::Serialize,
+    {
+        self.apply_common_metadata(resource.metadata_mut());
+        self.resources.push(Resource::new(&resource));
+        self
+    }
+
+    /// Add an object of a custom resource kind
+    pub fn add_dynamic_resource(&mut self, mut object: DynamicObject) -> &mut Self {
+        self.apply_common_metadata(&mut object.metadata);
+        self.resources.push(Resource::from_dynamic(&object));
+        self
+    }
+
+    pub fn add_plain_dynamic_resource<'ctx>(
         &'ctx mut self,
+        api_version: &str,
+        kind: &str,
         name: &str,
-        func: impl FnOnce(&mut k8s::Service),
+        func: impl FnOnce(&mut DynamicObject),
     ) -> &mut Self {
-        let mut service = k8s::Service::default();
-        service.metadata.merge_from(ObjectMeta {
+        let mut object = DynamicObject::new(api_version, kind);
+        object.metadata.merge_from(self.plain_metadata(name));
+
+        func(&mut object);
+
+        self.add_dynamic_resource(object)
+    }
+
+    /// Metadata every `add_plain_*` resource starts with
+    fn plain_metadata(&self, name: &str) -> ObjectMeta {
+        let mut metadata = ObjectMeta {
             name: Some(name.into()),
             labels: self.common_labels.to_owned().into(),
             ..Default::default()
-        });
-        service.metadata.labels.merge_from(
+        };
+        metadata.labels.merge_from(
             bmap! {
                 s!("template") => s!("plain"),
                 s!("app") => name.to_owned()
             }
             .into(),
         );
+        metadata
+    }
+
+    fn mark_standard(metadata: &mut ObjectMeta) {
+        metadata.labels.merge_from(
+            bmap! {
+                s!("template") => s!("standard")
+            }
+            .into(),
+        );
+    }
+
+    pub fn add_plain_service<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::Service),
+    ) -> &mut Self {
+        let mut service = k8s::Service::default();
+        service.metadata.merge_from(self.plain_metadata(name));
 
         func(&mut service);
 
-        self.resources.push(service.into());
-        self
+        self.add_resource(service)
     }
 
     pub fn add_standard_service<'ctx>(
@@ -120,7 +377,7 @@ impl GenContext {
             s.spec.get_or_insert_default().merge_from(ServiceSpec {
                 ports: vec![k8s::ServicePort {
                     name: "http".to_owned().into(),
-                    port: i32::from(3000),
+                    port: STANDARD_PORT,
                     ..Default::default()
                 }]
                 .into(),
@@ -149,7 +406,7 @@ impl GenContext {
             s.spec.get_or_insert_default().merge_from(ServiceSpec {
                 ports: vec![k8s::ServicePort {
                     name: "http".to_owned().into(),
-                    port: i32::from(3000),
+                    port: STANDARD_PORT,
                     node_port: i32::from(port).into(),
                     ..Default::default()
                 }]
@@ -168,23 +425,11 @@ impl GenContext {
     ) -> &mut Self {
         let mut deployment = k8s::Deployment::default();
 
-        deployment.metadata.merge_from(ObjectMeta {
-            name: Some(name.into()),
-            labels: self.common_labels.to_owned().into(),
-            ..Default::default()
-        });
-        deployment.metadata.labels.merge_from(
-            bmap! {
-                s!("template") => s!("plain"),
-                s!("app") => name.to_owned()
-            }
-            .into(),
-        );
+        deployment.metadata.merge_from(self.plain_metadata(name));
 
         func(&mut deployment);
 
-        self.resources.push(deployment.into());
-        self
+        self.add_resource(deployment)
     }
 
     pub fn add_standard_deployment<'ctx>(
@@ -194,6 +439,7 @@ impl GenContext {
         func: impl FnOnce(&mut k8s::Deployment),
     ) -> LabelSet {
         let pod_selector = self.new_labels().insert("app", name);
+        let admin_port = self.opts.admin_port;
 
         self.add_plain_deployment(name, |d| {
             d.metadata.labels.merge_from(pod_selector.to_owned().into());
@@ -206,6 +452,15 @@ impl GenContext {
             d.spec.get_or_insert_default().merge_from(DeploymentSpec {
                 replicas: Some(1),
                 selector: pod_selector.to_owned().into(),
+                strategy: DeploymentStrategy {
+                    type_: s!("RollingUpdate").into(),
+                    rolling_update: RollingUpdateDeployment {
+                        max_surge: k8s::IntOrString::Int(1).into(),
+                        max_unavailable: k8s::IntOrString::Int(0).into(),
+                    }
+                    .into(),
+                }
+                .into(),
                 template: PodTemplateSpec {
                     metadata: ObjectMeta {
                         labels: pod_selector.to_owned().into(),
@@ -214,29 +469,646 @@ impl GenContext {
                     }
                     .into(),
                     spec: PodSpec {
-                        containers: vec![Container {
-                            image: image.to_owned().into(),
-                            name: "main".to_owned(),
-                            ..Default::default()
-                        }],
+                        containers: vec![standard_container(image, admin_port)],
+                        security_context: standard_pod_security_context().into(),
+                        volumes: vec![standard_tmp_volume()].into(),
                         ..Default::default()
                     }
                     .into(),
-                    ..Default::default()
                 },
                 ..Default::default()
             });
+            // before `func`, so it can still change the probes
+            use_admin_port_for_probes(d);
             func(d);
         });
 
         pod_selector
     }
 
+    pub fn add_plain_config_map<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::ConfigMap),
+    ) -> &mut Self {
+        let mut config_map = k8s::ConfigMap::default();
+        config_map.metadata.merge_from(self.plain_metadata(name));
+
+        func(&mut config_map);
+
+        self.add_resource(config_map)
+    }
+
+    /// Reference a `Secret` managed outside of generated resources
+    ///
+    /// Secret values must never end up in generated yaml, so instead of
+    /// generating a `Secret`, workloads get to reference an existing one.
+    pub fn add_secret_ref(&self, name: &str) -> SecretRef {
+        SecretRef {
+            name: name.to_owned(),
+        }
+    }
+
+    /// Secret populated by external-secrets from AWS Secrets Manager
+    ///
+    /// Generates an `ExternalSecret` syncing `keys` of the Secrets Manager
+    /// secret `<shop>/<name>` (as set with `rustshop secret set <name> <key>`)
+    /// into a `Secret` called `name`, through the `--secret-store` store.
+    /// See `README.bootstrapping.md` for setting up external-secrets.
+    pub fn add_external_secret(&mut self, name: &str, keys: &[&str]) -> SecretRef {
+        let remote_key = format!("{}/{name}", self.shop_name().unwrap_or("rustshop"));
+        let secret_store = self.opts.secret_store.clone();
+
+        self.add_plain_dynamic_resource(EXTERNAL_SECRET_API_VERSION, "ExternalSecret", name, |o| {
+            o.set(
+                "spec",
+                serde_json::json!({
+                    "refreshInterval": "1h",
+                    "secretStoreRef": {
+                        "kind": "ClusterSecretStore",
+                        "name": secret_store,
+                    },
+                    "target": {
+                        "name": name,
+                        "creationPolicy": "Owner",
+                    },
+                    "data": keys.iter().map(|key| serde_json::json!({
+                        "secretKey": key,
+                        "remoteRef": {
+                            "key": remote_key,
+                            "property": key,
+                        },
+                    })).collect::<Vec<_>>(),
+                }),
+            );
+        });
+
+        self.add_secret_ref(name)
+    }
+
+    pub fn add_plain_service_account<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::ServiceAccount),
+    ) -> &mut Self {
+        let mut service_account = k8s::ServiceAccount::default();
+        service_account
+            .metadata
+            .merge_from(self.plain_metadata(name));
+
+        func(&mut service_account);
+
+        self.add_resource(service_account)
+    }
+
+    pub fn add_plain_ingress<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::Ingress),
+    ) -> &mut Self {
+        let mut ingress = k8s::Ingress::default();
+        ingress.metadata.merge_from(self.plain_metadata(name));
+
+        func(&mut ingress);
+
+        self.add_resource(ingress)
+    }
+
+    /// Ingress routing all `host` traffic to the `http` port of `service_name`
+    pub fn add_standard_ingress<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        host: &str,
+        service_name: &str,
+        func: impl FnOnce(&mut k8s::Ingress),
+    ) -> &mut Self {
+        self.add_plain_ingress(name, |i| {
+            Self::mark_standard(&mut i.metadata);
+            i.spec.get_or_insert_default().merge_from(IngressSpec {
+                rules: vec![IngressRule {
+                    host: host.to_owned().into(),
+                    http: HTTPIngressRuleValue {
+                        paths: vec![HTTPIngressPath {
+                            path: s!("/").into(),
+                            path_type: s!("Prefix"),
+                            backend: IngressBackend {
+                                service: IngressServiceBackend {
+                                    name: service_name.to_owned(),
+                                    port: ServiceBackendPort {
+                                        name: s!("http").into(),
+                                        ..Default::default()
+                                    }
+                                    .into(),
+                                }
+                                .into(),
+                                ..Default::default()
+                            },
+                        }],
+                    }
+                    .into(),
+                }]
+                .into(),
+                ..Default::default()
+            });
+            func(i);
+        })
+    }
+
+    /// Host name of a public endpoint: `<subdomain>.<cluster domain>`
+    ///
+    /// Fails if no cluster is selected, see [`Self::has_public_hosts`].
+    pub fn public_host(&self, subdomain: &str) -> GenResult<String> {
+        let cluster_domain = self
+            .env
+            .as_ref()
+            .and_then(|env| env.cluster_domain.as_deref())
+            .ok_or_else(|| {
+                Report::new(GenError)
+                    .attach_printable("Public endpoints require a rustshop cluster to be selected")
+            })?;
+        Ok(format!("{subdomain}.{cluster_domain}"))
+    }
+
+    /// Can public endpoints be added (is a cluster with a domain selected)
+    pub fn has_public_hosts(&self) -> bool {
+        matches!(&self.env, Some(env) if env.cluster_domain.is_some())
+    }
+
+    /// Expose pods matching `pod_selector` at `https://<subdomain>.<cluster domain>`
+    ///
+    /// Adds a standard `Service` and an `Ingress` with TLS certificate
+    /// issued by cert-manager (`--cluster-issuer`), and DNS record created
+    /// by external-dns in the cluster's Route53 zone (unless
+    /// `--no-external-dns`). See `README.bootstrapping.md` for installing
+    /// both in the cluster. Returns the host name.
+    pub fn add_public_endpoint(
+        &mut self,
+        name: &str,
+        pod_selector: &LabelSet,
+        subdomain: &str,
+    ) -> GenResult<String> {
+        let host = self.public_host(subdomain)?;
+
+        let mut annotations = bmap! {
+            s!("cert-manager.io/cluster-issuer") => self.opts.cluster_issuer.clone()
+        };
+        if !self.opts.no_external_dns {
+            annotations.insert(
+                s!("external-dns.alpha.kubernetes.io/hostname"),
+                host.clone(),
+            );
+        }
+
+        self.add_standard_service(name, pod_selector, |_| {});
+        self.add_standard_ingress(name, &host, name, |i| {
+            i.metadata.annotations.merge_from(annotations.into());
+            i.spec.get_or_insert_default().merge_from(IngressSpec {
+                tls: vec![IngressTLS {
+                    hosts: vec![host.clone()].into(),
+                    secret_name: format!("{name}-tls").into(),
+                }]
+                .into(),
+                ..Default::default()
+            });
+        });
+
+        Ok(host)
+    }
+
+    pub fn add_plain_horizontal_pod_autoscaler<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::HorizontalPodAutoscaler),
+    ) -> &mut Self {
+        let mut hpa = k8s::HorizontalPodAutoscaler::default();
+        hpa.metadata.merge_from(self.plain_metadata(name));
+
+        func(&mut hpa);
+
+        self.add_resource(hpa)
+    }
+
+    /// Autoscaler for the `Deployment` named `name`, scaling on CPU utilization
+    pub fn add_standard_horizontal_pod_autoscaler<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        min_replicas: i32,
+        max_replicas: i32,
+        cpu_utilization_percent: i32,
+        func: impl FnOnce(&mut k8s::HorizontalPodAutoscaler),
+    ) -> &mut Self {
+        self.add_plain_horizontal_pod_autoscaler(name, |h| {
+            Self::mark_standard(&mut h.metadata);
+            h.spec
+                .get_or_insert_default()
+                .merge_from(HorizontalPodAutoscalerSpec {
+                    scale_target_ref: CrossVersionObjectReference {
+                        api_version: s!("apps/v1").into(),
+                        kind: s!("Deployment"),
+                        name: name.to_owned(),
+                    },
+                    min_replicas: min_replicas.into(),
+                    max_replicas,
+                    metrics: vec![MetricSpec {
+                        type_: s!("Resource"),
+                        resource: ResourceMetricSource {
+                            name: s!("cpu"),
+                            target: MetricTarget {
+                                type_: s!("Utilization"),
+                                average_utilization: cpu_utilization_percent.into(),
+                                ..Default::default()
+                            },
+                        }
+                        .into(),
+                        ..Default::default()
+                    }]
+                    .into(),
+                    ..Default::default()
+                });
+            func(h);
+        })
+    }
+
+    pub fn add_plain_pod_disruption_budget<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::PodDisruptionBudget),
+    ) -> &mut Self {
+        let mut pdb = k8s::PodDisruptionBudget::default();
+        pdb.metadata.merge_from(self.plain_metadata(name));
+
+        func(&mut pdb);
+
+        self.add_resource(pdb)
+    }
+
+    /// Disruption budget allowing only one pod matching `pod_selector` to be unavailable
+    pub fn add_standard_pod_disruption_budget<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        pod_selector: &LabelSet,
+        func: impl FnOnce(&mut k8s::PodDisruptionBudget),
+    ) -> &mut Self {
+        self.add_plain_pod_disruption_budget(name, |p| {
+            p.metadata.labels.merge_from(pod_selector.to_owned().into());
+            Self::mark_standard(&mut p.metadata);
+            p.spec
+                .get_or_insert_default()
+                .merge_from(PodDisruptionBudgetSpec {
+                    max_unavailable: k8s::IntOrString::Int(1).into(),
+                    selector: pod_selector.to_owned().into(),
+                    ..Default::default()
+                });
+            func(p);
+        })
+    }
+
+    pub fn add_plain_job<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::Job),
+    ) -> &mut Self {
+        let mut job = k8s::Job::default();
+        job.metadata.merge_from(self.plain_metadata(name));
+
+        func(&mut job);
+
+        self.add_resource(job)
+    }
+
+    pub fn add_plain_cron_job<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::CronJob),
+    ) -> &mut Self {
+        let mut cron_job = k8s::CronJob::default();
+        cron_job.metadata.merge_from(self.plain_metadata(name));
+
+        func(&mut cron_job);
+
+        self.add_resource(cron_job)
+    }
+
+    /// Cron job running `image` on `schedule`, without overlapping runs
+    pub fn add_standard_cron_job<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        schedule: &str,
+        image: &str,
+        func: impl FnOnce(&mut k8s::CronJob),
+    ) -> &mut Self {
+        let pod_labels = self.new_labels().insert("app", name);
+
+        self.add_plain_cron_job(name, |c| {
+            Self::mark_standard(&mut c.metadata);
+            c.spec.get_or_insert_default().merge_from(CronJobSpec {
+                schedule: schedule.to_owned(),
+                concurrency_policy: s!("Forbid").into(),
+                job_template: JobTemplateSpec {
+                    spec: JobSpec {
+                        template: PodTemplateSpec {
+                            metadata: ObjectMeta {
+                                labels: pod_labels.into(),
+                                ..Default::default()
+                            }
+                            .into(),
+                            spec: PodSpec {
+                                containers: vec![Container {
+                                    image: image.to_owned().into(),
+                                    name: "main".to_owned(),
+                                    security_context: standard_security_context().into(),
+                                    volume_mounts: vec![standard_tmp_volume_mount()].into(),
+                                    ..Default::default()
+                                }],
+                                security_context: standard_pod_security_context().into(),
+                                volumes: vec![standard_tmp_volume()].into(),
+                                restart_policy: s!("OnFailure").into(),
+                                ..Default::default()
+                            }
+                            .into(),
+                        },
+                        ..Default::default()
+                    }
+                    .into(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+            func(c);
+        })
+    }
+
+    pub fn add_plain_network_policy<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        func: impl FnOnce(&mut k8s::NetworkPolicy),
+    ) -> &mut Self {
+        let mut network_policy = k8s::NetworkPolicy::default();
+        network_policy
+            .metadata
+            .merge_from(self.plain_metadata(name));
+
+        func(&mut network_policy);
+
+        self.add_resource(network_policy)
+    }
+
+    /// Allow traffic to `http` port of pods matching `pod_selector` only from
+    /// within the same namespace
+    pub fn add_standard_network_policy<'ctx>(
+        &'ctx mut self,
+        name: &str,
+        pod_selector: &LabelSet,
+        func: impl FnOnce(&mut k8s::NetworkPolicy),
+    ) -> &mut Self {
+        self.add_plain_network_policy(name, |n| {
+            n.metadata.labels.merge_from(pod_selector.to_owned().into());
+            Self::mark_standard(&mut n.metadata);
+            n.spec
+                .get_or_insert_default()
+                .merge_from(NetworkPolicySpec {
+                    pod_selector: pod_selector.to_owned().into(),
+                    policy_types: vec![s!("Ingress")].into(),
+                    ingress: vec![NetworkPolicyIngressRule {
+                        from: vec![NetworkPolicyPeer {
+                            pod_selector: k8s::LabelSelector::default().into(),
+                            ..Default::default()
+                        }]
+                        .into(),
+                        ports: vec![NetworkPolicyPort {
+                            port: k8s::IntOrString::String(s!("http")).into(),
+                            ..Default::default()
+                        }]
+                        .into(),
+                    }]
+                    .into(),
+                    ..Default::default()
+                });
+            func(n);
+        })
+    }
+
     pub fn new_labels(&self) -> LabelSet {
         LabelSet::default()
     }
 }
 
+/// Container of a standard deployment, running a `common-app` based service
+fn standard_container(image: &str, admin_port: Option<u16>) -> Container {
+    let http_probe = |path: &str, initial_delay_seconds: i32| Probe {
+        http_get: HTTPGetAction {
+            path: path.to_owned().into(),
+            port: k8s::IntOrString::String(s!("http")),
+            ..Default::default()
+        }
+        .into(),
+        initial_delay_seconds: initial_delay_seconds.into(),
+        period_seconds: 10.into(),
+        ..Default::default()
+    };
+
+    Container {
+        image: image.to_owned().into(),
+        name: "main".to_owned(),
+        ports: vec![ContainerPort {
+            name: s!("http").into(),
+            container_port: STANDARD_PORT,
+            ..Default::default()
+        }]
+        .into(),
+        env: [
+            Some(k8s::EnvVar {
+                name: s!("LISTEN_PORT"),
+                value: STANDARD_PORT.to_string().into(),
+                ..Default::default()
+            }),
+            admin_port.map(|port| k8s::EnvVar {
+                name: STANDARD_ADMIN_LISTEN_ENV.to_owned(),
+                value: format!("0.0.0.0:{port}").into(),
+                ..Default::default()
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .into(),
+        liveness_probe: http_probe(STANDARD_LIVENESS_PATH, 10).into(),
+        readiness_probe: http_probe(STANDARD_READINESS_PATH, 0).into(),
+        resources: ResourceRequirements {
+            requests: bmap! {
+                s!("cpu") => Quantity(s!("50m")),
+                s!("memory") => Quantity(s!("64Mi"))
+            }
+            .into(),
+            limits: bmap! {
+                s!("cpu") => Quantity(s!("500m")),
+                s!("memory") => Quantity(s!("256Mi"))
+            }
+            .into(),
+            ..Default::default()
+        }
+        .into(),
+        security_context: standard_security_context().into(),
+        volume_mounts: vec![standard_tmp_volume_mount()].into(),
+        ..Default::default()
+    }
+}
+
+/// Containers of standard workloads can't escalate privileges or write outside of `/tmp`
+fn standard_security_context() -> SecurityContext {
+    SecurityContext {
+        allow_privilege_escalation: false.into(),
+        read_only_root_filesystem: true.into(),
+        capabilities: Capabilities {
+            drop: vec![s!("ALL")].into(),
+            ..Default::default()
+        }
+        .into(),
+        ..Default::default()
+    }
+}
+
+/// Pods of standard workloads run as non-root
+fn standard_pod_security_context() -> PodSecurityContext {
+    PodSecurityContext {
+        run_as_non_root: true.into(),
+        run_as_user: STANDARD_RUN_AS_USER.into(),
+        run_as_group: STANDARD_RUN_AS_USER.into(),
+        ..Default::default()
+    }
+}
+
+/// Writable `/tmp`, as the root filesystem is read-only
+fn standard_tmp_volume() -> Volume {
+    Volume {
+        name: s!("tmp"),
+        empty_dir: EmptyDirVolumeSource::default().into(),
+        ..Default::default()
+    }
+}
+
+fn standard_tmp_volume_mount() -> VolumeMount {
+    VolumeMount {
+        name: s!("tmp"),
+        mount_path: s!("/tmp"),
+        ..Default::default()
+    }
+}
+
+/// Point probes at the admin listener, if the container enables it
+///
+/// With [`STANDARD_ADMIN_LISTEN_ENV`] set (see `--admin-port`), `common-app`
+/// serves health checks only there, so probes on the `http` port would fail.
+/// Only plain `env` values are considered (not ones coming from secrets or
+/// config maps).
+fn use_admin_port_for_probes(deployment: &mut k8s::Deployment) {
+    let pod_spec = match deployment
+        .spec
+        .as_mut()
+        .and_then(|spec| spec.template.spec.as_mut())
+    {
+        Some(pod_spec) => pod_spec,
+        None => return,
+    };
+
+    for container in &mut pod_spec.containers {
+        let admin_port = container
+            .env
+            .iter()
+            .flatten()
+            .find(|var| var.name == STANDARD_ADMIN_LISTEN_ENV)
+            .and_then(|var| var.value.as_deref())
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .map(|addr| i32::from(addr.port()));
+        let admin_port = match admin_port {
+            Some(port) => port,
+            None => continue,
+        };
+
+        let ports = container.ports.get_or_insert_default();
+        if !ports
+            .iter()
+            .any(|port| port.name.as_deref() == Some("admin"))
+        {
+            ports.push(ContainerPort {
+                name: s!("admin").into(),
+                container_port: admin_port,
+                ..Default::default()
+            });
+        }
+
+        for probe in [
+            &mut container.liveness_probe,
+            &mut container.readiness_probe,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Some(http_get) = probe.http_get.as_mut() {
+                http_get.port = k8s::IntOrString::String(s!("admin"));
+            }
+        }
+    }
+}
+
+/// Reference to a `Secret` created and populated outside of the generator
+#[derive(Clone, Debug)]
+pub struct SecretRef {
+    name: String,
+}
+
+impl SecretRef {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Expose all the keys of the secret as env vars in every container of `deployment`
+    pub fn add_env_from_to(&self, deployment: &mut k8s::Deployment) {
+        let pod_spec = deployment
+            .spec
+            .get_or_insert_default()
+            .template
+            .spec
+            .get_or_insert_default();
+        for container in &mut pod_spec.containers {
+            container
+                .env_from
+                .get_or_insert_default()
+                .push(self.env_from());
+        }
+    }
+
+    /// Env var `env_name` set to the value of `key` in the secret
+    pub fn env_var(&self, env_name: &str, key: &str) -> k8s::EnvVar {
+        k8s::EnvVar {
+            name: env_name.to_owned(),
+            value_from: EnvVarSource {
+                secret_key_ref: SecretKeySelector {
+                    name: self.name.to_owned().into(),
+                    key: key.to_owned(),
+                    ..Default::default()
+                }
+                .into(),
+                ..Default::default()
+            }
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    /// All the keys of the secret as env vars
+    pub fn env_from(&self) -> k8s::EnvFromSource {
+        k8s::EnvFromSource {
+            secret_ref: SecretEnvSource {
+                name: self.name.to_owned().into(),
+                ..Default::default()
+            }
+            .into(),
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct LabelSet(BTreeMap<String, String>);
 
@@ -319,28 +1191,188 @@ pub struct GenError;
 
 pub type GenResult<T> = Result<T, GenError>;
 
-pub fn run_resource_generator(mut gen: impl Generator) -> GenResult<()> {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer().with_writer(io::stderr))
-        .init();
-
+pub fn run_resource_generator<G: Generator>(mut gen: G) -> GenResult<()> {
     let opts = Opts::from_args();
 
-    let mut ctx = GenContext::new(opts.common);
+    let _tracing_guard =
+        common_tracing::init(&opts.common.tracing, G::NAME).change_context(GenError)?;
+
+    let env = GenEnv::load(&opts.common)?;
+
+    let ctx = generate_resources(&mut gen, opts.common, &opts.custom, env)?;
+
+    let command = match opts.command {
+        Some(command) => command,
+        None => {
+            return output::write_resources(
+                &ctx.resources,
+                ctx.opts.format,
+                ctx.opts.out_dir.as_deref(),
+            )
+        }
+    };
+
+    let target = kubectl::Target::new(ctx.env())?;
+    match command {
+        Command::Apply => kubectl::apply(&target, &ctx.resources, G::NAME),
+        Command::Diff => {
+            if kubectl::diff(&target, &ctx.resources, G::NAME)? {
+                // like `kubectl diff`, so it's usable in scripts
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Prune { dry_run } => kubectl::prune(&target, &ctx.resources, G::NAME, dry_run),
+    }
+}
+
+/// Run the generator, and sort and validate the generated resources
+fn generate_resources<G: Generator>(
+    gen: &mut G,
+    common_opts: opts::CommonOpts,
+    opts: &G::Opts,
+    env: Option<GenEnv>,
+) -> GenResult<GenContext> {
+    let mut ctx = GenContext::new(common_opts, env, G::NAME);
+
+    gen.generate(&mut ctx, opts)?;
 
-    gen.generate(&mut ctx, &opts.custom)?;
+    output::sort_resources(&mut ctx.resources);
 
-    for resource in ctx.resources {
-        println!(
-            "{}",
-            serde_yaml::to_string(&resource)
-                .report()
-                .change_context(GenError)?
+    if !ctx.opts.skip_validation {
+        let violations = validate::validate(&ctx.resources, &ctx.policy_rules);
+        if !violations.is_empty() {
+            for violation in &violations {
+                error!("Policy violation: {violation}");
+            }
+            return Err(Report::new(GenError).attach_printable(format!(
+                "Generated resources failed validation ({} violations)",
+                violations.len()
+            )));
+        }
+    }
+
+    Ok(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct TestOpts {
+        #[clap(flatten)]
+        common: opts::CommonOpts,
+    }
+
+    fn ctx() -> GenContext {
+        ctx_with(&[])
+    }
+
+    fn ctx_with(args: &[&str]) -> GenContext {
+        let opts = TestOpts::parse_from(std::iter::once("test").chain(args.iter().copied()));
+        GenContext::new(opts.common, None, "test")
+    }
+
+    fn pod_spec(ctx: &GenContext) -> PodSpec {
+        let resource = &ctx.resources[0];
+        if let Some(deployment) = resource.to_typed::<k8s::Deployment>() {
+            deployment.spec.unwrap().template.spec.unwrap()
+        } else if let Some(cron_job) = resource.to_typed::<k8s::CronJob>() {
+            cron_job
+                .spec
+                .unwrap()
+                .job_template
+                .spec
+                .unwrap()
+                .template
+                .spec
+                .unwrap()
+        } else {
+            panic!("unexpected {}", resource.kind())
+        }
+    }
+
+    fn probe_ports(container: &Container) -> Vec<k8s::IntOrString> {
+        [&container.liveness_probe, &container.readiness_probe]
+            .into_iter()
+            .map(|probe| probe.clone().unwrap().http_get.unwrap().port)
+            .collect()
+    }
+
+    #[test]
+    fn probes_use_the_http_port_by_default() {
+        let mut ctx = ctx();
+        ctx.add_standard_deployment("app", "app:1", |_| {});
+
+        let container = &pod_spec(&ctx).containers[0];
+        assert_eq!(
+            probe_ports(container),
+            vec![k8s::IntOrString::String(s!("http")); 2]
+        );
+    }
+
+    #[test]
+    fn probes_follow_the_admin_listener() {
+        let mut ctx = ctx_with(&["--admin-port", "3001"]);
+        ctx.add_standard_deployment("app", "app:1", |_| {});
+
+        let container = &pod_spec(&ctx).containers[0];
+        assert_eq!(
+            probe_ports(container),
+            vec![k8s::IntOrString::String(s!("admin")); 2]
         );
+        let admin_port = container
+            .ports
+            .iter()
+            .flatten()
+            .find(|port| port.name.as_deref() == Some("admin"))
+            .unwrap();
+        assert_eq!(admin_port.container_port, 3001);
+        let admin_listen = container
+            .env
+            .iter()
+            .flatten()
+            .find(|var| var.name == STANDARD_ADMIN_LISTEN_ENV)
+            .unwrap();
+        assert_eq!(admin_listen.value.as_deref(), Some("0.0.0.0:3001"));
     }
 
-    Ok(())
+    #[test]
+    fn closure_overrides_probes() {
+        let mut ctx = ctx_with(&["--admin-port", "3001"]);
+        ctx.add_standard_deployment("app", "app:1", |d| {
+            let pod_spec = d.spec.as_mut().unwrap().template.spec.as_mut().unwrap();
+            let probe = pod_spec.containers[0].liveness_probe.as_mut().unwrap();
+            probe.http_get.as_mut().unwrap().port = k8s::IntOrString::Int(8080);
+        });
+
+        let container = &pod_spec(&ctx).containers[0];
+        assert_eq!(
+            probe_ports(container),
+            [
+                k8s::IntOrString::Int(8080),
+                k8s::IntOrString::String(s!("admin"))
+            ]
+        );
+    }
+
+    #[test]
+    fn cron_jobs_get_security_defaults() {
+        let mut ctx = ctx();
+        ctx.add_standard_cron_job("job", "0 * * * *", "job:1", |_| {});
+
+        let pod_spec = pod_spec(&ctx);
+        assert_eq!(
+            pod_spec.security_context,
+            Some(standard_pod_security_context())
+        );
+        assert_eq!(
+            pod_spec.containers[0].security_context,
+            Some(standard_security_context())
+        );
+        assert_eq!(pod_spec.volumes, Some(vec![standard_tmp_volume()]));
+    }
 }
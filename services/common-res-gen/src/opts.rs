@@ -1,4 +1,7 @@
-use clap::{Args, FromArgMatches, Parser};
+use std::path::PathBuf;
+
+use clap::{ArgEnum, Args, FromArgMatches, Parser, Subcommand};
+use common_tracing::TracingOpts;
 
 #[derive(Parser, Debug, Clone)]
 // help_template to basically disable `resource-common` showing up as the name
@@ -13,11 +16,84 @@ where
 
     #[clap(flatten)]
     pub custom: GeneratorOpts,
+
+    /// Without a command, generated resources are printed
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Apply generated resources to the current cluster (server-side apply)
+    Apply,
+    /// Show what `apply` would change in the current cluster
+    Diff,
+    /// Delete resources applied by this generator before, but not generated anymore
+    Prune {
+        /// Only print what would be deleted
+        #[clap(long = "dry-run")]
+        dry_run: bool,
+    },
 }
 
 #[derive(Args, Debug, Clone)]
 pub struct CommonOpts {
-    test: bool,
+    /// Generate for this rustshop account instead of the current one
+    #[clap(long = "account")]
+    pub account: Option<String>,
+
+    /// Generate for this rustshop cluster instead of the current one
+    #[clap(long = "cluster")]
+    pub cluster: Option<String>,
+
+    /// Output format
+    #[clap(long = "format", arg_enum, default_value = "yaml")]
+    pub format: OutputFormat,
+
+    /// Don't fail on policy violations in generated resources
+    #[clap(long = "skip-validation")]
+    pub skip_validation: bool,
+
+    /// Write each resource to a separate file in this directory (with a `kustomization.yaml`)
+    #[clap(long = "out-dir", parse(from_os_str))]
+    pub out_dir: Option<PathBuf>,
+
+    /// external-secrets `ClusterSecretStore` external secrets are synced from
+    #[clap(
+        long = "secret-store",
+        env = "RUSTSHOP_SECRET_STORE",
+        default_value = crate::EXTERNAL_SECRETS_STORE
+    )]
+    pub secret_store: String,
+
+    /// cert-manager `ClusterIssuer` issuing certificates of public endpoints
+    #[clap(
+        long = "cluster-issuer",
+        env = "RUSTSHOP_CLUSTER_ISSUER",
+        default_value = crate::CERT_MANAGER_CLUSTER_ISSUER
+    )]
+    pub cluster_issuer: String,
+
+    /// Don't annotate public endpoints for external-dns (manage their DNS records manually)
+    #[clap(long = "no-external-dns")]
+    pub no_external_dns: bool,
+
+    /// Serve health checks of standard deployments on this separate admin port
+    #[clap(long = "admin-port", env = "RUSTSHOP_ADMIN_PORT")]
+    pub admin_port: Option<u16>,
+
+    #[clap(flatten)]
+    pub tracing: TracingOpts,
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy)]
+pub enum OutputFormat {
+    /// Multi-document yaml stream
+    Yaml,
+    /// Concatenated json objects
+    Json,
+    /// A single json `List` object
+    JsonList,
 }
 
 impl<GeneratorOpts> Opts<GeneratorOpts>
@@ -27,4 +103,12 @@ where
     pub fn from_args() -> Self {
         Opts::parse()
     }
+
+    pub fn try_from_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        Opts::try_parse_from(args)
+    }
 }
@@ -0,0 +1,141 @@
+//! Writing generated resources out
+//!
+//! Either to stdout, as a single stream `kubectl apply -f -` can consume,
+//! or to a directory, one file per resource, along with a
+//! `kustomization.yaml` listing them.
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use error_stack::{IntoReport, ResultExt};
+
+use crate::{opts::OutputFormat, GenError, GenResult, Resource};
+
+/// Sort resources into a stable order: by kind, then by name
+pub fn sort_resources(resources: &mut [Resource]) {
+    resources.sort_by(|a, b| {
+        (a.kind(), a.name(), a.namespace()).cmp(&(b.kind(), b.name(), b.namespace()))
+    });
+}
+
+fn to_yaml_document(resource: &Resource) -> GenResult<String> {
+    let yaml = serde_yaml::to_string(resource)
+        .report()
+        .change_context(GenError)?;
+    // `serde_yaml` might or might not start the document with a separator
+    let yaml = yaml.strip_prefix("---\n").unwrap_or(&yaml);
+    Ok(format!("---\n{yaml}"))
+}
+
+fn to_json_document(resource: &Resource) -> GenResult<String> {
+    let mut json = serde_json::to_string_pretty(resource)
+        .report()
+        .change_context(GenError)?;
+    json.push('\n');
+    Ok(json)
+}
+
+/// Write all the `resources` to `out` as a single stream
+pub fn write_stream(
+    resources: &[Resource],
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> GenResult<()> {
+    let text = match format {
+        OutputFormat::Yaml => resources
+            .iter()
+            .map(to_yaml_document)
+            .collect::<GenResult<String>>()?,
+        OutputFormat::Json => resources
+            .iter()
+            .map(to_json_document)
+            .collect::<GenResult<String>>()?,
+        OutputFormat::JsonList => {
+            let list = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "List",
+                "items": resources,
+            });
+            let mut json = serde_json::to_string_pretty(&list)
+                .report()
+                .change_context(GenError)?;
+            json.push('\n');
+            json
+        }
+    };
+
+    out.write_all(text.as_bytes())
+        .report()
+        .change_context(GenError)?;
+    Ok(())
+}
+
+/// File name of a resource in the output directory, e.g. `deployment-starter.yaml`
+fn resource_file_name(resource: &Resource, format: OutputFormat) -> String {
+    let ext = match format {
+        OutputFormat::Yaml => "yaml",
+        OutputFormat::Json | OutputFormat::JsonList => "json",
+    };
+    let mut name = resource.kind().to_lowercase();
+    if let Some(namespace) = resource.namespace() {
+        name.push('-');
+        name.push_str(namespace);
+    }
+    name.push('-');
+    name.push_str(resource.name().unwrap_or("unnamed"));
+    format!("{name}.{ext}")
+}
+
+/// Write every resource to its own file in `dir`, plus a `kustomization.yaml`
+///
+/// With `--out-dir`, `json-list` is the same as `json`: one object per file.
+pub fn write_dir(resources: &[Resource], format: OutputFormat, dir: &Path) -> GenResult<()> {
+    fs::create_dir_all(dir).report().change_context(GenError)?;
+
+    let mut file_names = vec![];
+    for resource in resources {
+        let file_name = resource_file_name(resource, format);
+        let text = match format {
+            OutputFormat::Yaml => to_yaml_document(resource)?,
+            OutputFormat::Json | OutputFormat::JsonList => to_json_document(resource)?,
+        };
+        write_file(&dir.join(&file_name), &text)?;
+        file_names.push(file_name);
+    }
+
+    let kustomization = serde_json::json!({
+        "apiVersion": "kustomize.config.k8s.io/v1beta1",
+        "kind": "Kustomization",
+        "resources": file_names,
+    });
+    write_file(
+        &dir.join("kustomization.yaml"),
+        &serde_yaml::to_string(&kustomization)
+            .report()
+            .change_context(GenError)?,
+    )?;
+
+    Ok(())
+}
+
+fn write_file(path: &Path, text: &str) -> GenResult<()> {
+    fs::write(path, text)
+        .report()
+        .change_context(GenError)
+        .attach_printable_lazy(|| format!("path: {}", path.display()))
+}
+
+/// Write resources according to the common options
+pub fn write_resources(
+    resources: &[Resource],
+    format: OutputFormat,
+    out_dir: Option<&Path>,
+) -> GenResult<()> {
+    if let Some(dir) = out_dir {
+        write_dir(resources, format, dir)
+    } else {
+        write_stream(resources, format, &mut io::stdout().lock())
+    }
+}
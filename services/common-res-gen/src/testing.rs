@@ -0,0 +1,93 @@
+//! Helpers for testing generators
+//!
+//! [`generate`] runs a [`Generator`] in-process and returns the resources,
+//! and [`assert_snapshot`] compares them with a yaml file checked into the
+//! repository. Set `BLESS=1` to (re)write snapshot files instead.
+//!
+//! ```ignore
+//! let resources = testing::generate(&mut Gen, &["--image", "starter:1"])?;
+//! testing::assert_snapshot("tests/snapshots/default.yaml", &resources);
+//! ```
+use std::{fs, path::Path};
+
+use error_stack::{IntoReport, ResultExt};
+
+use crate::{
+    generate_resources, output, GenEnv, GenError, GenResult, Generator, Opts, OutputFormat,
+    Resource,
+};
+
+/// Env var enabling snapshot updates
+pub const BLESS_ENV_NAME: &str = "BLESS";
+
+/// Run `gen` with command line `args`, outside of any rustshop environment
+pub fn generate<G: Generator>(gen: &mut G, args: &[&str]) -> GenResult<Vec<Resource>> {
+    generate_with_env(gen, None, args)
+}
+
+/// Like [`generate`], but as if running in the given rustshop environment
+pub fn generate_with_env<G: Generator>(
+    gen: &mut G,
+    env: Option<GenEnv>,
+    args: &[&str],
+) -> GenResult<Vec<Resource>> {
+    let opts = Opts::<G::Opts>::try_from_args(std::iter::once(G::NAME).chain(args.iter().copied()))
+        .report()
+        .change_context(GenError)?;
+
+    let ctx = generate_resources(gen, opts.common, &opts.custom, env)?;
+
+    Ok(ctx.resources)
+}
+
+/// Render resources the same way a generator would print them
+pub fn to_yaml(resources: &[Resource]) -> GenResult<String> {
+    let mut out = vec![];
+    output::write_stream(resources, OutputFormat::Yaml, &mut out)?;
+    Ok(String::from_utf8(out).expect("yaml is utf8"))
+}
+
+fn is_blessing() -> bool {
+    std::env::var(BLESS_ENV_NAME)
+        .map(|v| !v.is_empty() && v != "0")
+        .unwrap_or(false)
+}
+
+/// Compare `resources` with the snapshot file at `path`
+///
+/// Panics on a mismatch (or a missing snapshot), unless blessing.
+pub fn assert_snapshot(path: impl AsRef<Path>, resources: &[Resource]) {
+    let path = path.as_ref();
+    let actual = to_yaml(resources).expect("resources serialize");
+
+    if is_blessing() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("create snapshot dir");
+        }
+        fs::write(path, &actual).expect("write snapshot");
+        return;
+    }
+
+    let expected = match fs::read_to_string(path) {
+        Ok(expected) => expected,
+        Err(e) => panic!(
+            "Could not read snapshot {}: {e}; run with `{BLESS_ENV_NAME}=1` to create it",
+            path.display()
+        ),
+    };
+
+    if expected != actual {
+        let line = expected
+            .lines()
+            .zip(actual.lines())
+            .position(|(e, a)| e != a)
+            .unwrap_or_else(|| expected.lines().count().min(actual.lines().count()));
+        panic!(
+            "Snapshot {} does not match, first difference at line {}:\n- {}\n+ {}\n\nRun with `{BLESS_ENV_NAME}=1` to update it",
+            path.display(),
+            line + 1,
+            expected.lines().nth(line).unwrap_or("<end of file>"),
+            actual.lines().nth(line).unwrap_or("<end of file>"),
+        );
+    }
+}
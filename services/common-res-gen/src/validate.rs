@@ -0,0 +1,484 @@
+//! Validation of generated resources before they are written out
+//!
+//! Every [`PolicyRule`] inspects the whole set of generated resources and
+//! reports [`Violation`]s. Built-in rules catch common generator mistakes;
+//! generators can add their own with [`crate::GenContext::add_policy_rule`].
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::{k8s, Resource};
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub rule: String,
+    pub kind: String,
+    pub name: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}/{}: {}",
+            self.rule, self.kind, self.name, self.message
+        )
+    }
+}
+
+pub trait PolicyRule {
+    /// Short name, used when reporting violations
+    fn name(&self) -> &str;
+
+    fn check(&self, resources: &[Resource]) -> Vec<Violation>;
+}
+
+fn violation(rule: &dyn PolicyRule, resource: &Resource, message: impl Into<String>) -> Violation {
+    Violation {
+        rule: rule.name().to_owned(),
+        kind: resource.kind().to_owned(),
+        name: resource.name().unwrap_or_default().to_owned(),
+        message: message.into(),
+    }
+}
+
+pub fn default_rules() -> Vec<Box<dyn PolicyRule>> {
+    vec![
+        Box::new(UniqueNames),
+        Box::new(SelectorsMatchPods),
+        Box::new(PortReferences),
+        Box::new(PinnedImages),
+    ]
+}
+
+pub fn validate(resources: &[Resource], rules: &[Box<dyn PolicyRule>]) -> Vec<Violation> {
+    rules
+        .iter()
+        .flat_map(|rule| rule.check(resources))
+        .collect()
+}
+
+/// Pods some workload (`Deployment`, `Job`, `CronJob`) will create
+struct PodTemplate<'r> {
+    resource: &'r Resource,
+    labels: BTreeMap<String, String>,
+    spec: k8s::PodSpec,
+}
+
+fn pod_templates(resources: &[Resource]) -> Vec<PodTemplate<'_>> {
+    let mut templates = vec![];
+    for resource in resources {
+        let template = if let Some(d) = resource.to_typed::<k8s::Deployment>() {
+            d.spec.map(|spec| spec.template)
+        } else if let Some(j) = resource.to_typed::<k8s::Job>() {
+            j.spec.map(|spec| spec.template)
+        } else if let Some(c) = resource.to_typed::<k8s::CronJob>() {
+            c.spec
+                .and_then(|spec| spec.job_template.spec)
+                .map(|spec| spec.template)
+        } else {
+            None
+        };
+
+        if let Some(template) = template {
+            templates.push(PodTemplate {
+                resource,
+                labels: template.metadata.and_then(|m| m.labels).unwrap_or_default(),
+                spec: template.spec.unwrap_or_default(),
+            });
+        }
+    }
+    templates
+}
+
+fn selector_matches(
+    selector: &BTreeMap<String, String>,
+    labels: &BTreeMap<String, String>,
+) -> bool {
+    selector
+        .iter()
+        .all(|(name, value)| labels.get(name) == Some(value))
+}
+
+/// Names must be unique per kind (and namespace)
+pub struct UniqueNames;
+
+impl PolicyRule for UniqueNames {
+    fn name(&self) -> &str {
+        "unique-names"
+    }
+
+    fn check(&self, resources: &[Resource]) -> Vec<Violation> {
+        let mut seen = BTreeSet::new();
+        let mut violations = vec![];
+        for resource in resources {
+            if resource.name().is_none() {
+                violations.push(violation(self, resource, "missing name"));
+                continue;
+            }
+            if !seen.insert((resource.kind(), resource.namespace(), resource.name())) {
+                violations.push(violation(self, resource, "duplicate name"));
+            }
+        }
+        violations
+    }
+}
+
+/// Selectors must select some pods, and workloads must select their own pods
+pub struct SelectorsMatchPods;
+
+impl PolicyRule for SelectorsMatchPods {
+    fn name(&self) -> &str {
+        "selectors-match-pods"
+    }
+
+    fn check(&self, resources: &[Resource]) -> Vec<Violation> {
+        let templates = pod_templates(resources);
+        let mut violations = vec![];
+
+        let selects_any = |selector: &BTreeMap<String, String>| {
+            templates
+                .iter()
+                .any(|t| selector_matches(selector, &t.labels))
+        };
+
+        for resource in resources {
+            if let Some(d) = resource.to_typed::<k8s::Deployment>() {
+                let spec = d.spec.unwrap_or_default();
+                let selector = spec.selector.match_labels.unwrap_or_default();
+                let labels = spec
+                    .template
+                    .metadata
+                    .and_then(|m| m.labels)
+                    .unwrap_or_default();
+                if selector.is_empty() {
+                    violations.push(violation(self, resource, "empty selector"));
+                } else if !selector_matches(&selector, &labels) {
+                    violations.push(violation(
+                        self,
+                        resource,
+                        format!("selector {selector:?} does not match pod labels {labels:?}"),
+                    ));
+                }
+            } else if let Some(s) = resource.to_typed::<k8s::Service>() {
+                let spec = s.spec.unwrap_or_default();
+                // services without a selector (e.g. `ExternalName`) are managed manually
+                if let Some(selector) = spec.selector.filter(|s| !s.is_empty()) {
+                    if !selects_any(&selector) {
+                        violations.push(violation(
+                            self,
+                            resource,
+                            format!("selector {selector:?} does not match any generated pods"),
+                        ));
+                    }
+                }
+            } else if let Some(p) = resource.to_typed::<k8s::PodDisruptionBudget>() {
+                let selector = p
+                    .spec
+                    .and_then(|spec| spec.selector)
+                    .and_then(|s| s.match_labels)
+                    .unwrap_or_default();
+                if !selects_any(&selector) {
+                    violations.push(violation(
+                        self,
+                        resource,
+                        format!("selector {selector:?} does not match any generated pods"),
+                    ));
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+/// Named ports must exist where they are referenced
+///
+/// Checks `Service` target ports against the containers of the selected pods,
+/// and `Ingress` backends against generated services.
+pub struct PortReferences;
+
+impl PolicyRule for PortReferences {
+    fn name(&self) -> &str {
+        "port-references"
+    }
+
+    fn check(&self, resources: &[Resource]) -> Vec<Violation> {
+        let templates = pod_templates(resources);
+        let services: BTreeMap<String, k8s::ServiceSpec> = resources
+            .iter()
+            .filter_map(|r| r.to_typed::<k8s::Service>())
+            .filter_map(|s| Some((s.metadata.name?, s.spec.unwrap_or_default())))
+            .collect();
+        let mut violations = vec![];
+
+        for resource in resources {
+            if let Some(s) = resource.to_typed::<k8s::Service>() {
+                let spec = s.spec.unwrap_or_default();
+                let selector = match spec.selector.filter(|s| !s.is_empty()) {
+                    Some(selector) => selector,
+                    None => continue,
+                };
+                let container_port_names: BTreeSet<String> = templates
+                    .iter()
+                    .filter(|t| selector_matches(&selector, &t.labels))
+                    .flat_map(|t| t.spec.containers.iter())
+                    .flat_map(|c| c.ports.iter().flatten())
+                    .filter_map(|p| p.name.clone())
+                    .collect();
+
+                for port in spec.ports.unwrap_or_default() {
+                    if let Some(k8s::IntOrString::String(target)) = port.target_port {
+                        if !container_port_names.contains(&target) {
+                            violations.push(violation(
+                                self,
+                                resource,
+                                format!("target port `{target}` not defined by selected pods"),
+                            ));
+                        }
+                    }
+                }
+            } else if let Some(i) = resource.to_typed::<k8s::Ingress>() {
+                let spec = i.spec.unwrap_or_default();
+                let backends = spec
+                    .rules
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|rule| rule.http)
+                    .flat_map(|http| http.paths)
+                    .map(|path| path.backend)
+                    .chain(spec.default_backend)
+                    .filter_map(|backend| backend.service);
+
+                for backend in backends {
+                    let service = match services.get(&backend.name) {
+                        Some(service) => service,
+                        None => {
+                            violations.push(violation(
+                                self,
+                                resource,
+                                format!("backend service `{}` is not generated", backend.name),
+                            ));
+                            continue;
+                        }
+                    };
+                    let port = backend.port.unwrap_or_default();
+                    let exists = service.ports.iter().flatten().any(|p| {
+                        (port.name.is_none() || p.name == port.name)
+                            && (port.number.is_none() || port.number == Some(p.port))
+                    });
+                    if !exists {
+                        violations.push(violation(
+                            self,
+                            resource,
+                            format!("backend service `{}` has no port {port:?}", backend.name),
+                        ));
+                    }
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+/// Images must be pinned to a tag other than `latest`, or to a digest
+pub struct PinnedImages;
+
+impl PinnedImages {
+    fn is_pinned(image: &str) -> bool {
+        if image.contains('@') {
+            return true;
+        }
+        // the part after the last `/` (so registry ports are not mistaken for tags)
+        let name = image.rsplit('/').next().unwrap_or(image);
+        match name.split_once(':') {
+            Some((_, tag)) => !tag.is_empty() && tag != "latest",
+            None => false,
+        }
+    }
+}
+
+impl PolicyRule for PinnedImages {
+    fn name(&self) -> &str {
+        "pinned-images"
+    }
+
+    fn check(&self, resources: &[Resource]) -> Vec<Violation> {
+        pod_templates(resources)
+            .iter()
+            .flat_map(|t| {
+                t.spec
+                    .containers
+                    .iter()
+                    .chain(t.spec.init_containers.iter().flatten())
+                    .map(move |c| (t.resource, c))
+            })
+            .filter_map(|(resource, container)| {
+                let image = container.image.as_deref().unwrap_or_default();
+                (!Self::is_pinned(image)).then(|| {
+                    violation(
+                        self,
+                        resource,
+                        format!(
+                            "container `{}` image `{image}` is not pinned to a tag or digest",
+                            container.name
+                        ),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn resource<T>(value: serde_json::Value) -> Resource
+    where
+        T: k8s_openapi::Resource + serde::Serialize + serde::de::DeserializeOwned,
+    {
+        Resource::new(&serde_json::from_value::<T>(value).expect("valid object"))
+    }
+
+    fn deployment(name: &str, image: &str, port_name: &str) -> Resource {
+        resource::<k8s::Deployment>(json!({
+            "metadata": {"name": name},
+            "spec": {
+                "selector": {"matchLabels": {"app": name}},
+                "template": {
+                    "metadata": {"labels": {"app": name}},
+                    "spec": {"containers": [{
+                        "name": name,
+                        "image": image,
+                        "ports": [{"name": port_name, "containerPort": 3000}],
+                    }]},
+                },
+            },
+        }))
+    }
+
+    fn service(name: &str, app: &str, target_port: &str) -> Resource {
+        resource::<k8s::Service>(json!({
+            "metadata": {"name": name},
+            "spec": {
+                "selector": {"app": app},
+                "ports": [{"name": "http", "port": 80, "targetPort": target_port}],
+            },
+        }))
+    }
+
+    fn ingress(service: &str, port_name: &str) -> Resource {
+        resource::<k8s::Ingress>(json!({
+            "metadata": {"name": "ingress"},
+            "spec": {"rules": [{"http": {"paths": [{
+                "path": "/",
+                "pathType": "Prefix",
+                "backend": {"service": {"name": service, "port": {"name": port_name}}},
+            }]}}]},
+        }))
+    }
+
+    fn violated(rule: &dyn PolicyRule, resources: &[Resource]) -> Vec<String> {
+        rule.check(resources)
+            .iter()
+            .map(|v| format!("{}/{}", v.kind, v.name))
+            .collect()
+    }
+
+    #[test]
+    fn unique_names() {
+        let app = deployment("app", "app:1", "http");
+        assert!(violated(&UniqueNames, &[app.clone(), service("app", "app", "http")]).is_empty());
+        assert_eq!(
+            violated(&UniqueNames, &[app.clone(), app]),
+            ["Deployment/app"]
+        );
+    }
+
+    #[test]
+    fn selectors_match_pods() {
+        let app = deployment("app", "app:1", "http");
+        assert!(violated(
+            &SelectorsMatchPods,
+            &[app.clone(), service("app", "app", "http")]
+        )
+        .is_empty());
+
+        let mismatched = resource::<k8s::Deployment>(json!({
+            "metadata": {"name": "worker"},
+            "spec": {
+                "selector": {"matchLabels": {"app": "worker"}},
+                "template": {"metadata": {"labels": {"app": "other"}}},
+            },
+        }));
+        assert_eq!(
+            violated(
+                &SelectorsMatchPods,
+                &[app, mismatched, service("orphan", "nothing", "http")]
+            ),
+            ["Deployment/worker", "Service/orphan"]
+        );
+    }
+
+    #[test]
+    fn port_references() {
+        let app = deployment("app", "app:1", "http");
+        assert!(violated(
+            &PortReferences,
+            &[
+                app.clone(),
+                service("app", "app", "http"),
+                ingress("app", "http")
+            ]
+        )
+        .is_empty());
+
+        assert_eq!(
+            violated(
+                &PortReferences,
+                &[
+                    app,
+                    service("app", "app", "metrics"),
+                    ingress("app", "grpc"),
+                    ingress("missing", "http"),
+                ]
+            ),
+            ["Service/app", "Ingress/ingress", "Ingress/ingress"]
+        );
+    }
+
+    #[test]
+    fn pinned_images() {
+        assert!(violated(&PinnedImages, &[deployment("app", "app:1", "http")]).is_empty());
+        assert_eq!(
+            violated(&PinnedImages, &[deployment("app", "app:latest", "http")]),
+            ["Deployment/app"]
+        );
+    }
+
+    #[test]
+    fn is_pinned() {
+        for pinned in [
+            "app:1.2",
+            "registry.io/team/app:1.2",
+            "registry.io:5000/app:1.2",
+            "app@sha256:0123abcd",
+            "registry.io:5000/app@sha256:0123abcd",
+        ] {
+            assert!(PinnedImages::is_pinned(pinned), "{pinned}");
+        }
+        for unpinned in [
+            "app",
+            "app:",
+            "app:latest",
+            "registry.io:5000/app",
+            "registry.io:5000/app:latest",
+        ] {
+            assert!(!PinnedImages::is_pinned(unpinned), "{unpinned}");
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Logging and tracing setup shared by services and resource generators
+//!
+//! Logs go to stderr, as text or json (`--log-format`), filtered with
+//! `RUST_LOG`. With the `otlp` feature spans can additionally be exported to
+//! an OpenTelemetry collector (`--otlp-endpoint`), and incoming W3C
+//! `traceparent` headers are honored (see [`set_remote_parent`]).
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+use clap::{ArgEnum, Args};
+use error_stack::{IntoReport, ResultExt};
+use thiserror::Error;
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer};
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable
+    Text,
+    /// One json object per line, including the current span fields
+    Json,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TracingOpts {
+    /// Log output format
+    #[clap(
+        long = "log-format",
+        arg_enum,
+        default_value = "text",
+        env = "LOG_FORMAT"
+    )]
+    pub log_format: LogFormat,
+
+    /// Export spans to this OTLP/gRPC collector (e.g. `http://localhost:4317`)
+    #[cfg(feature = "otlp")]
+    #[clap(long = "otlp-endpoint", env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Error, Debug)]
+#[error("Tracing setup error")]
+pub struct TracingError;
+
+pub type TracingResult<T> = error_stack::Result<T, TracingError>;
+
+/// Flushes spans not exported yet when dropped
+///
+/// Keep it alive until the end of `main`.
+#[derive(Debug)]
+#[must_use]
+pub struct TracingGuard {
+    log_filter: LogFilterHandle,
+    #[cfg(feature = "otlp")]
+    otlp: bool,
+}
+
+impl TracingGuard {
+    pub fn log_filter(&self) -> &LogFilterHandle {
+        &self.log_filter
+    }
+}
+
+/// Allows changing the `RUST_LOG`-style filter at runtime
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    directives: Arc<Mutex<String>>,
+    reload: Arc<ReloadFn>,
+}
+
+type ReloadFn = dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync;
+
+impl LogFilterHandle {
+    fn new<S: 'static>(directives: String, handle: reload::Handle<EnvFilter, S>) -> Self {
+        Self {
+            directives: Arc::new(Mutex::new(directives)),
+            reload: Arc::new(move |filter| handle.reload(filter)),
+        }
+    }
+
+    /// Current filter directives
+    pub fn get(&self) -> String {
+        self.directives.lock().expect("lock not poisoned").clone()
+    }
+
+    /// Replace the filter, e.g. with `info,common_app=debug`
+    pub fn set(&self, directives: &str) -> TracingResult<()> {
+        let filter = EnvFilter::try_new(directives)
+            .report()
+            .change_context(TracingError)
+            .attach_printable_lazy(|| format!("Invalid filter: {directives}"))?;
+        (self.reload)(filter)
+            .report()
+            .change_context(TracingError)?;
+        *self.directives.lock().expect("lock not poisoned") = directives.to_owned();
+        Ok(())
+    }
+}
+
+impl fmt::Debug for LogFilterHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogFilterHandle")
+            .field("directives", &self.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if self.otlp {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+fn env_filter_directives() -> String {
+    std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into())
+}
+
+/// Install the global tracing subscriber
+///
+/// `service_name` identifies the exported spans. Spans are exported in the
+/// background on the current tokio runtime, so without one (e.g. in
+/// synchronous resource generators) OTLP export is skipped with a warning.
+pub fn init(opts: &TracingOpts, service_name: &str) -> TracingResult<TracingGuard> {
+    let fmt_layer = match opts.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(io::stderr)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(io::stderr)
+            .boxed(),
+    };
+
+    let directives = env_filter_directives();
+    let (filter_layer, filter_handle) = reload::Layer::new(EnvFilter::new(&directives));
+    let log_filter = LogFilterHandle::new(directives, filter_handle);
+
+    let registry = tracing_subscriber::registry().with(fmt_layer);
+
+    #[cfg(feature = "otlp")]
+    {
+        let has_runtime = tokio::runtime::Handle::try_current().is_ok();
+        let otel_layer = opts
+            .otlp_endpoint
+            .as_deref()
+            .filter(|_| has_runtime)
+            .map(|endpoint| otlp::tracer(endpoint, service_name))
+            .transpose()?
+            .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+        let otlp = otel_layer.is_some();
+
+        registry.with(otel_layer).with(filter_layer).init();
+        otlp::set_propagator();
+
+        if opts.otlp_endpoint.is_some() && !has_runtime {
+            tracing::warn!("Not in a tokio runtime; OTLP export disabled");
+        }
+
+        Ok(TracingGuard { log_filter, otlp })
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        let _ = service_name;
+        registry.with(filter_layer).init();
+
+        Ok(TracingGuard { log_filter })
+    }
+}
+
+/// Make `span` a child of the remote span passed in W3C `traceparent` headers
+///
+/// Without the `otlp` feature (or any `traceparent`) does nothing.
+pub fn set_remote_parent(span: &tracing::Span, headers: &http::HeaderMap) {
+    #[cfg(feature = "otlp")]
+    otlp::set_remote_parent(span, headers);
+
+    #[cfg(not(feature = "otlp"))]
+    let _ = (span, headers);
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use error_stack::{IntoReport, ResultExt};
+    use opentelemetry::{
+        global,
+        sdk::{propagation::TraceContextPropagator, trace, Resource},
+        KeyValue,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use crate::{TracingError, TracingResult};
+
+    pub(crate) fn tracer(endpoint: &str, service_name: &str) -> TracingResult<trace::Tracer> {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(
+                trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    service_name.to_owned(),
+                )])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)
+            .report()
+            .change_context(TracingError)
+            .attach_printable_lazy(|| format!("OTLP endpoint: {endpoint}"))
+    }
+
+    pub(crate) fn set_propagator() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+    }
+
+    pub(crate) fn set_remote_parent(span: &tracing::Span, headers: &http::HeaderMap) {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&opentelemetry_http::HeaderExtractor(headers))
+        });
+        span.set_parent(parent);
+    }
+}
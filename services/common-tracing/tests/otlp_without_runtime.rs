@@ -0,0 +1,18 @@
+#![cfg(feature = "otlp")]
+
+use common_tracing::{LogFormat, TracingOpts};
+
+/// Resource generators are synchronous, but share `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// with the services
+#[test]
+fn skips_otlp_outside_of_a_runtime() {
+    let opts = TracingOpts {
+        log_format: LogFormat::Text,
+        otlp_endpoint: Some("http://127.0.0.1:4317".into()),
+    };
+    let guard = common_tracing::init(&opts, "test").unwrap();
+
+    tracing::info_span!("not exported").in_scope(|| tracing::info!("still logged"));
+
+    drop(guard);
+}
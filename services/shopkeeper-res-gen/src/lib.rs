@@ -0,0 +1,86 @@
+//! Kubernetes resources of the `shopkeeper` service
+#![feature(option_get_or_insert_default)]
+
+use clap::Args;
+use common_res_gen::{k8s, GenContext, GenResult};
+
+#[derive(Args, Debug, Clone)]
+pub struct Opts {
+    #[clap(long, env = "STARTER_IMAGE")]
+    image: String,
+    #[clap(long = "node-port")]
+    node_port: Option<u16>,
+    /// Public endpoint subdomain (of the cluster domain)
+    #[clap(long = "subdomain", default_value = "shopkeeper")]
+    subdomain: String,
+    /// GitHub webhook URL, if not the one of the public endpoint
+    #[clap(long = "webhook-url")]
+    webhook_url: Option<String>,
+}
+
+pub struct Gen;
+
+impl common_res_gen::Generator for Gen {
+    const NAME: &'static str = env!("CARGO_PKG_NAME");
+
+    type Opts = Opts;
+
+    fn generate(&mut self, ctx: &mut GenContext, opts: &Self::Opts) -> GenResult<()> {
+        let app_name = "shopkeeper";
+
+        // Without a cluster (or when exposed on a node port) there's no public
+        // endpoint to derive the webhook URL from
+        let public_host = if opts.node_port.is_none() && ctx.has_public_hosts() {
+            Some(ctx.public_host(&opts.subdomain)?)
+        } else {
+            None
+        };
+        let webhook_url = opts.webhook_url.clone().or_else(|| {
+            public_host
+                .as_ref()
+                .map(|host| format!("https://{host}/github/webhook"))
+        });
+
+        let secret = ctx.add_external_secret(
+            app_name,
+            &[
+                "SHOPKEEPER_GITHUB_USERNAME",
+                "SHOPKEEPER_GITHUB_ACCESS_TOKEN",
+                "SHOPKEEPER_GITHUB_WEBHOOK_SECRET",
+                "DATABASE_URL",
+            ],
+        );
+
+        let selector = ctx.add_standard_deployment(app_name, &opts.image, |d| {
+            secret.add_env_from_to(d);
+
+            let webhook_url = if let Some(webhook_url) = webhook_url.as_ref() {
+                webhook_url
+            } else {
+                return;
+            };
+            let pod_spec = d
+                .spec
+                .get_or_insert_default()
+                .template
+                .spec
+                .get_or_insert_default();
+            for container in &mut pod_spec.containers {
+                container.env.get_or_insert_default().push(k8s::EnvVar {
+                    name: "SHOPKEEPER_GITHUB_WEBHOOK_URL".into(),
+                    value: webhook_url.clone().into(),
+                    ..Default::default()
+                });
+            }
+        });
+        if let Some(node_port) = opts.node_port {
+            ctx.add_node_port_service(app_name, node_port, &selector, |_| {});
+        } else if public_host.is_some() {
+            ctx.add_public_endpoint(app_name, &selector, &opts.subdomain)?;
+        } else {
+            ctx.add_standard_service(app_name, &selector, |_| {});
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+//! `apply`, `diff` and `prune` against a stub `kubectl` on `PATH`
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+/// Records its arguments (and stdin of `apply`/`diff`), and answers `get`
+/// with `existing.json` and `config view` with the `apps` namespace
+const STUB_KUBECTL: &str = r#"#!/bin/sh
+echo "$@" >> "$STUB_DIR/args"
+case "$1" in
+    apply) cat > "$STUB_DIR/apply.yaml" ;;
+    diff) cat > "$STUB_DIR/diff.yaml"; exit "${STUB_DIFF_EXIT:-0}" ;;
+    get) cat "$STUB_DIR/existing.json" ;;
+    config) echo apps ;;
+esac
+"#;
+
+struct Stub {
+    dir: PathBuf,
+}
+
+impl Stub {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "shopkeeper-res-gen-kubectl-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let kubectl = dir.join("kubectl");
+        fs::write(&kubectl, STUB_KUBECTL).unwrap();
+        fs::set_permissions(&kubectl, fs::Permissions::from_mode(0o755)).unwrap();
+
+        Self { dir }
+    }
+
+    fn existing(&self, json: &str) {
+        fs::write(self.dir.join("existing.json"), json).unwrap();
+    }
+
+    fn run(&self, args: &[&str], diff_exit: i32) -> Output {
+        let path = std::env::join_paths(std::iter::once(self.dir.clone()).chain(
+            std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default()),
+        ))
+        .unwrap();
+
+        Command::new(env!("CARGO_BIN_EXE_shopkeeper-res-gen"))
+            .args(["--image", "shopkeeper:1"])
+            .args(args)
+            .env("PATH", path)
+            .env("STUB_DIR", &self.dir)
+            .env("STUB_DIFF_EXIT", diff_exit.to_string())
+            // outside of any rustshop environment
+            .env_remove("RUSTSHOP_ROOT")
+            .env_remove("RUSTSHOP_ACCOUNT")
+            .env_remove("RUSTSHOP_CLUSTER")
+            .env_remove("RUSTSHOP_NAMESPACE")
+            .output()
+            .unwrap()
+    }
+
+    fn file(&self, name: &str) -> String {
+        fs::read_to_string(self.dir.join(name)).unwrap_or_default()
+    }
+
+    fn calls(&self) -> Vec<String> {
+        self.file("args").lines().map(ToOwned::to_owned).collect()
+    }
+}
+
+impl Drop for Stub {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn existing_deployment(name: &str, namespace: &str) -> String {
+    format!(
+        r#"{{"kind": "Deployment", "metadata": {{"name": "{name}", "namespace": "{namespace}"}}}}"#
+    )
+}
+
+fn contains_generated(path: &Path) -> bool {
+    let yaml = fs::read_to_string(path).unwrap_or_default();
+    yaml.contains("kind: Deployment") && yaml.contains("rustshop/generator: shopkeeper-res-gen")
+}
+
+#[test]
+fn apply() {
+    let stub = Stub::new("apply");
+
+    assert_success(&stub.run(&["apply"], 0));
+
+    assert_eq!(
+        stub.calls(),
+        ["apply --server-side --field-manager shopkeeper-res-gen -f -"]
+    );
+    assert!(contains_generated(&stub.dir.join("apply.yaml")));
+}
+
+#[test]
+fn diff() {
+    let stub = Stub::new("diff");
+
+    assert_success(&stub.run(&["diff"], 0));
+    assert_eq!(
+        stub.calls(),
+        ["diff --server-side --field-manager shopkeeper-res-gen -f -"]
+    );
+    assert!(contains_generated(&stub.dir.join("diff.yaml")));
+
+    assert_eq!(stub.run(&["diff"], 1).status.code(), Some(1));
+    assert!(!stub.run(&["diff"], 2).status.success());
+}
+
+#[test]
+fn prune() {
+    let stub = Stub::new("prune");
+    stub.existing(&format!(
+        r#"{{"items": [{}, {}, {}]}}"#,
+        existing_deployment("shopkeeper", "apps"),
+        existing_deployment("old", "apps"),
+        // generated ones go to the default namespace
+        existing_deployment("shopkeeper", "other"),
+    ));
+
+    assert_success(&stub.run(&["prune", "--dry-run"], 0));
+    assert!(!stub.calls().iter().any(|call| call.starts_with("delete")));
+
+    fs::remove_file(stub.dir.join("args")).unwrap();
+    assert_success(&stub.run(&["prune"], 0));
+
+    let calls = stub.calls();
+    assert_eq!(calls[0], "config view --minify -o jsonpath={..namespace}");
+    assert!(calls[1].starts_with("get "));
+    assert!(calls[1].contains(" --all-namespaces "));
+    assert!(calls[1].ends_with(" -l rustshop/generator=shopkeeper-res-gen -o json"));
+    assert_eq!(
+        calls[2..],
+        [
+            "delete Deployment/old -n apps",
+            "delete Deployment/shopkeeper -n other",
+        ]
+    );
+}
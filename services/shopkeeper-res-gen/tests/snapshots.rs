@@ -0,0 +1,57 @@
+use common_res_gen::{testing, GenEnv};
+use shopkeeper_res_gen::Gen;
+
+fn prod_env() -> GenEnv {
+    GenEnv {
+        shop_name: "rustshop".into(),
+        shop_domain: "rustshop.org".into(),
+        account: Some("prod".into()),
+        cluster: Some("prod".into()),
+        cluster_domain: Some("prod.k8s.rustshop.org".into()),
+        kube_ctx: Some("prod".into()),
+        namespace: None,
+    }
+}
+
+#[test]
+fn without_cluster() {
+    let resources = testing::generate(&mut Gen, &["--image", "shopkeeper:1"]).unwrap();
+    testing::assert_snapshot("tests/snapshots/without-cluster.yaml", &resources);
+}
+
+#[test]
+fn public_endpoint() {
+    let resources =
+        testing::generate_with_env(&mut Gen, Some(prod_env()), &["--image", "shopkeeper:1"])
+            .unwrap();
+    testing::assert_snapshot("tests/snapshots/public-endpoint.yaml", &resources);
+}
+
+#[test]
+fn node_port() {
+    let resources = testing::generate_with_env(
+        &mut Gen,
+        Some(prod_env()),
+        &["--image", "shopkeeper:1", "--node-port", "30080"],
+    )
+    .unwrap();
+    testing::assert_snapshot("tests/snapshots/node-port.yaml", &resources);
+}
+
+#[test]
+fn node_port_with_webhook_url() {
+    let resources = testing::generate_with_env(
+        &mut Gen,
+        Some(prod_env()),
+        &[
+            "--image",
+            "shopkeeper:1",
+            "--node-port",
+            "30080",
+            "--webhook-url",
+            "https://hooks.rustshop.org/github/webhook",
+        ],
+    )
+    .unwrap();
+    testing::assert_snapshot("tests/snapshots/node-port-webhook-url.yaml", &resources);
+}
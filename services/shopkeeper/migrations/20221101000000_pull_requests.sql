@@ -0,0 +1,17 @@
+CREATE TABLE pull_requests (
+    repo TEXT NOT NULL,
+    number BIGINT NOT NULL,
+    head_sha TEXT NOT NULL,
+    state TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (repo, number)
+);
+
+CREATE TABLE merge_queue (
+    id BIGSERIAL PRIMARY KEY,
+    repo TEXT NOT NULL,
+    pr_number BIGINT NOT NULL,
+    enqueued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (repo, pr_number),
+    FOREIGN KEY (repo, pr_number) REFERENCES pull_requests (repo, number) ON DELETE CASCADE
+);
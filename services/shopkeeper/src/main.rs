@@ -1,16 +1,13 @@
-use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
-    body::{self},
-    http::{HeaderMap, HeaderValue},
     response::Html,
     routing::{get, post},
     Extension,
 };
 use clap::Args;
-use common_app::{
-    AppResult, RequestError, RequestResult, WebhookVerificationError, WebhookVerificationResult,
-};
+use common_app::{AppResult, GitHub, RequestResult, RouteLimits, VerifiedWebhook};
+use sqlx::migrate::Migrator;
 
 #[derive(Args, Debug, Clone)]
 pub struct Opts {
@@ -26,32 +23,37 @@ pub struct Opts {
     )]
     webhook_secret: String,
 
+    /// Public URL GitHub delivers webhooks to, if exposed publicly
     #[clap(long = "github-webhook-url", env = "SHOPKEEPER_GITHUB_WEBHOOK_URL")]
-    webhook_url: String,
+    webhook_url: Option<String>,
 }
 
-pub struct State {
-    opts: Opts,
-}
+const GITHUB_WEBHOOK_PATH: &str = "/github/webhook";
 
-impl State {
-    pub fn new(opts: Opts) -> Self {
-        Self { opts }
-    }
-}
+static MIGRATOR: Migrator = sqlx::migrate!();
 
 #[tokio::main]
 async fn main() -> AppResult<()> {
-    let app = common_app::AppBuilder::new();
-    let (app, opts) = app.parse_opts::<Opts>();
+    let app = common_app::AppBuilder::new().set_migrator(&MIGRATOR);
+    let (app, opts) = app.parse_opts::<Opts>()?;
+    // GitHub sends payloads of up to 25MB
+    let app = app.set_route_limits(
+        GITHUB_WEBHOOK_PATH,
+        RouteLimits::new()
+            .timeout(Duration::from_secs(120))
+            .max_body_size(25 * 1024 * 1024),
+    );
+
+    let (app, db) = app.connect_database().await?;
 
-    let shared_state = Arc::new(State::new(opts));
+    let github_webhook = GitHub::new(&opts.webhook_secret);
 
     app.run_axum(|router| {
         Ok(router
             .route("/", get(handler))
-            .route("/github/webhook", post(github_webhook_handler))
-            .layer(Extension(shared_state)))
+            .route(GITHUB_WEBHOOK_PATH, post(github_webhook_handler))
+            .layer(Extension(github_webhook))
+            .layer(Extension(db)))
     })
     .await?;
 
@@ -63,57 +65,42 @@ async fn handler() -> Html<&'static str> {
 }
 
 async fn github_webhook_handler(
-    Extension(state): Extension<Arc<State>>,
-    headers: HeaderMap,
-    body: body::Bytes,
+    webhook: VerifiedWebhook<GitHub>,
 ) -> RequestResult<Html<&'static str>> {
-    verify_github_request(
-        Some(&state.opts.webhook_secret),
-        &body,
-        headers.get("x-hub-signature-256"),
-    )
-    .map_err(RequestError::from)?;
+    let payload = webhook.json::<serde_json::Value>()?;
     println!(
         "{}",
-        serde_json::to_string_pretty(
-            &serde_json::from_slice::<serde_json::Value>(&body)
-                .map_err(|_| RequestError::InternalError)?
-        )
-        .map_err(|_| RequestError::InternalError)?
+        serde_json::to_string_pretty(&payload).expect("json value serializes")
     );
     Ok(Html("<h1>Hello, World!</h1>"))
 }
 
-pub fn verify_github_request(
-    secret: Option<&str>,
-    payload: &[u8],
-    tag: Option<&HeaderValue>,
-) -> WebhookVerificationResult<()> {
-    use ring::hmac;
-    if let Some(secret) = secret {
-        if let Some(tag) = tag {
-            let sha256_eq_prefix = b"sha256=";
-            let tag = tag.as_bytes();
-            let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
-            let tagbytes = hex::decode(&tag[sha256_eq_prefix.len()..]).map_err(|_| {
-                WebhookVerificationError::InvalidTag {
-                    tag: tag.to_owned(),
-                }
-            })?;
-            if tag.starts_with(sha256_eq_prefix) {
-                hmac::verify(&key, payload, tagbytes.as_slice()).map_err(|_| {
-                    WebhookVerificationError::InvalidTag {
-                        tag: tag.to_owned(),
-                    }
-                })?;
-            } else {
-                Err(WebhookVerificationError::InvalidTag {
-                    tag: tag.to_owned(),
-                })?
-            }
-        } else {
-            Err(WebhookVerificationError::MissingTag)?
-        }
+#[cfg(test)]
+mod tests {
+    use common_app::db::TestDatabase;
+
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see `TEST_DATABASE_URL`"]
+    async fn migrations_apply() {
+        let db = TestDatabase::new(&MIGRATOR).await.unwrap();
+
+        sqlx::query(
+            "INSERT INTO pull_requests (repo, number, head_sha, state) VALUES ($1, $2, $3, $4)",
+        )
+        .bind("rustshop/rustshop")
+        .bind(1i64)
+        .bind("0000000")
+        .bind("open")
+        .execute(db.pool())
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO merge_queue (repo, pr_number) VALUES ($1, $2)")
+            .bind("rustshop/rustshop")
+            .bind(1i64)
+            .execute(db.pool())
+            .await
+            .unwrap();
     }
-    Ok(())
 }
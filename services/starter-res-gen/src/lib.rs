@@ -0,0 +1,34 @@
+//! Kubernetes resources of the `starter` service
+#![feature(option_get_or_insert_default)]
+
+use clap::Args;
+use common_res_gen::{GenContext, GenResult};
+
+#[derive(Args, Debug, Clone)]
+pub struct Opts {
+    #[clap(long, env = "STARTER_IMAGE")]
+    image: String,
+    #[clap(long = "node-port")]
+    node_port: Option<u16>,
+}
+
+pub struct Gen;
+
+impl common_res_gen::Generator for Gen {
+    const NAME: &'static str = env!("CARGO_PKG_NAME");
+
+    type Opts = Opts;
+
+    fn generate(&mut self, ctx: &mut GenContext, opts: &Self::Opts) -> GenResult<()> {
+        let app_name = "starter";
+
+        let selector = ctx.add_standard_deployment(app_name, &opts.image, |_| {});
+        if let Some(node_port) = opts.node_port {
+            ctx.add_node_port_service(app_name, node_port, &selector, |_| {});
+        } else {
+            ctx.add_standard_service(app_name, &selector, |_| {});
+        }
+
+        Ok(())
+    }
+}
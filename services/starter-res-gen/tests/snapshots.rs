@@ -0,0 +1,15 @@
+use common_res_gen::testing;
+use starter_res_gen::Gen;
+
+#[test]
+fn default() {
+    let resources = testing::generate(&mut Gen, &["--image", "starter:1"]).unwrap();
+    testing::assert_snapshot("tests/snapshots/default.yaml", &resources);
+}
+
+#[test]
+fn node_port() {
+    let resources =
+        testing::generate(&mut Gen, &["--image", "starter:1", "--node-port", "30080"]).unwrap();
+    testing::assert_snapshot("tests/snapshots/node-port.yaml", &resources);
+}
@@ -1,17 +1,35 @@
-use axum::{response::Html, routing::get};
+use axum::{response::Html, routing::get, Extension};
 use common_app::AppResult;
+use serde::Deserialize;
+use tokio::sync::watch;
+
+/// `[reloadable]` table of the config file
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct Settings {
+    greeting: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            greeting: "Hello, World!".into(),
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> AppResult<()> {
     let app = common_app::AppBuilder::new();
-    let (app, _opts) = app.parse_opts::<common_app::NoOpts>();
+    let (app, _opts) = app.parse_opts::<common_app::NoOpts>()?;
+    let settings = app.reloadable_config::<Settings>()?;
 
-    app.run_axum(|router| Ok(router.route("/", get(handler))))
+    app.run_axum(|router| Ok(router.route("/", get(handler)).layer(Extension(settings))))
         .await?;
 
     Ok(())
 }
 
-async fn handler() -> Html<&'static str> {
-    Html("<h1>Hello, World!</h1>")
+async fn handler(Extension(settings): Extension<watch::Receiver<Settings>>) -> Html<String> {
+    Html(format!("<h1>{}</h1>", settings.borrow().greeting))
 }